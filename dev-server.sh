@@ -2,6 +2,7 @@
 
 export HTTP_PORT=4080
 export EXTERNAL_BASE=hopper
+export HOST_META_OVERRIDES=etc/host-meta-overrides.json
 
 RUST_BACKTRACE=1 RUST_LOG=debug RUST_LIB_BACKTRACE=1 cargo run
 
@@ -1,14 +1,25 @@
 use anyhow::Result;
+use hickory_resolver::{
+    config::ResolverConfig, name_server::TokioConnectionProvider, TokioResolver,
+};
 use hopper::{
-    cache::{new_resolve_aturi_cache, new_resolve_webhostmeta_cache, ResolveWebHostMetaResult},
+    cache::{
+        new_resolve_aturi_cache, new_resolve_webfinger_cache, new_resolve_webhostmeta_cache,
+        Resolver, ResolverOptions,
+    },
+    config::{LogFormat, OverrideSource},
+    egress::{redirect_policy, PublicResolver, ServerPolicy},
     http::{
         context::{AppEngine, WebContext},
         server::build_router,
         templates,
     },
-    webhostmeta::WebHostMeta,
+    identity::IdentityResolver,
+    overrides::HostMetaOverrides,
+    store::{open_store, SHARED_STORE_MEMORY_TTL},
+    telemetry,
 };
-use std::{env, time::Duration};
+use std::{env, sync::Arc, time::Duration};
 use tokio::net::TcpListener;
 use tokio::signal;
 use tokio_util::{sync::CancellationToken, task::TaskTracker};
@@ -16,13 +27,6 @@ use tracing_subscriber::prelude::*;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    tracing_subscriber::registry()
-        .with(tracing_subscriber::EnvFilter::new(
-            std::env::var("RUST_LOG").unwrap_or_else(|_| "hopper=debug,info".into()),
-        ))
-        .with(tracing_subscriber::fmt::layer().pretty())
-        .init();
-
     let version = hopper::config::version()?;
 
     env::args().for_each(|arg| {
@@ -34,69 +38,153 @@ async fn main() -> Result<()> {
 
     let config = hopper::config::Config::new()?;
 
-    let mut client_builder = reqwest::Client::builder();
+    let fmt_layer = tracing_subscriber::fmt::layer();
+    let fmt_layer = match config.log_format {
+        LogFormat::Pretty => fmt_layer.pretty().boxed(),
+        LogFormat::Compact => fmt_layer.compact().boxed(),
+        LogFormat::Json => fmt_layer.json().with_span_list(true).boxed(),
+    };
+    tracing_subscriber::registry()
+        .with(tracing_subscriber::EnvFilter::new(
+            std::env::var("RUST_LOG").unwrap_or_else(|_| "hopper=debug,info".into()),
+        ))
+        .with(fmt_layer)
+        .init();
+
+    let dns_resolver = match TokioResolver::builder_tokio() {
+        Ok(builder) => builder.build(),
+        Err(err) => {
+            tracing::warn!(error = ?err, "Unable to read system DNS configuration");
+            TokioResolver::builder_with_config(
+                ResolverConfig::default(),
+                TokioConnectionProvider::default(),
+            )
+            .build()
+        }
+    };
+
+    let server_policy = ServerPolicy::new(&config.server_allow_list, &config.server_deny_list);
+
+    let mut ca_certificates = Vec::new();
     for ca_certificate in config.certificate_bundles.as_ref() {
         tracing::info!("Loading CA certificate: {:?}", ca_certificate);
         let cert = std::fs::read(ca_certificate)?;
-        let cert = reqwest::Certificate::from_pem(&cert)?;
-        client_builder = client_builder.add_root_certificate(cert);
+        ca_certificates.push(reqwest::Certificate::from_pem(&cert)?);
     }
 
-    client_builder = client_builder.user_agent(config.user_agent.clone());
-    client_builder = client_builder.read_timeout(Duration::from_secs(1));
-    client_builder = client_builder.connect_timeout(Duration::from_secs(1));
-    client_builder = client_builder.timeout(Duration::from_secs(3));
+    let new_client_builder = || {
+        let mut client_builder = reqwest::Client::builder();
+        for cert in &ca_certificates {
+            client_builder = client_builder.add_root_certificate(cert.clone());
+        }
+        client_builder
+            .user_agent(config.user_agent.clone())
+            .read_timeout(Duration::from_secs(1))
+            .connect_timeout(Duration::from_secs(1))
+            .timeout(Duration::from_secs(3))
+    };
+
+    // The PLC directory is trusted configuration and may be a local service,
+    // so it does not go through the public address and server policy checks.
+    let plc_client = new_client_builder().build()?;
+
+    let mut client_builder = new_client_builder();
+    client_builder = client_builder.redirect(redirect_policy(server_policy.clone()));
+    if config.allow_private_addresses {
+        tracing::warn!("Requests to private addresses are allowed");
+    } else {
+        client_builder =
+            client_builder.dns_resolver(Arc::new(PublicResolver::new(dns_resolver.clone())));
+    }
     let http_client = client_builder.build()?;
 
     let jinja = templates::build_env(config.external_base.clone(), config.version.clone());
 
-    let resolve_webfinger_cache = new_resolve_webhostmeta_cache();
-
-    resolve_webfinger_cache
-        .insert(
-            "bsky.app".to_string(),
-            ResolveWebHostMetaResult::Found(WebHostMeta::new(vec![
-                hopper::webhostmeta::Link::new("https://bsky.app/profile/{authority}", None),
-                hopper::webhostmeta::Link::new(
-                    "https://bsky.app/profile/{authority}/post/{rkey}",
-                    Some("app.bsky.feed.post"),
-                ),
-            ])),
-        )
-        .await;
-
-    resolve_webfinger_cache
-        .insert(
-            "frontpage.fyi".to_string(),
-            ResolveWebHostMetaResult::Found(WebHostMeta::new(vec![
-                hopper::webhostmeta::Link::new(
-                    "https://frontpage.fyi/post/{authority}/{rkey}",
-                    Some("fyi.unravel.frontpage.post"),
-                ),
-            ])),
-        )
-        .await;
-
-        resolve_webfinger_cache
-        .insert(
-            "whtwnd.com".to_string(),
-            ResolveWebHostMetaResult::Found(WebHostMeta::new(vec![
-                hopper::webhostmeta::Link::new(
-                    "https://whtwnd.com/{authority}/{rkey}",
-                    Some("com.whtwnd.blog.entry"),
-                ),
-            ])),
-        )
-        .await;
-
-    let resolve_aturi_cache = new_resolve_aturi_cache();
+    let host_meta_overrides = match &config.host_meta_overrides {
+        OverrideSource::Bundled => {
+            let overrides = HostMetaOverrides::bundled(config.host_meta_overrides_mode)?;
+            tracing::info!("Loaded {} bundled host-meta overrides", overrides.len());
+            overrides
+        }
+        OverrideSource::File(path) => {
+            let overrides = HostMetaOverrides::load(path, config.host_meta_overrides_mode)?;
+            tracing::info!(
+                "Loaded {} host-meta overrides from {:?}",
+                overrides.len(),
+                path
+            );
+            overrides
+        }
+        OverrideSource::Disabled => HostMetaOverrides::default(),
+    };
 
-    let web_context = WebContext::new(
-        config.external_base.as_str(),
-        AppEngine::from(jinja),
+    let default_servers = if config.append_default_servers {
+        config.default_servers.as_ref().clone()
+    } else {
+        Vec::new()
+    };
+
+    let metrics = telemetry::install_recorder(&default_servers)?;
+
+    let store = match config.cache_store.as_ref() {
+        Some(location) => Some(open_store(location).await?),
+        None => None,
+    };
+
+    // Entries from a shared store are read again after a short time, so that
+    // changes made by other instances are picked up.
+    let memory_ttl = store
+        .as_ref()
+        .filter(|store| store.is_shared())
+        .map(|_| SHARED_STORE_MEMORY_TTL);
+
+    let resolve_webhostmeta_cache = new_resolve_webhostmeta_cache(memory_ttl);
+
+    let resolve_webfinger_cache = new_resolve_webfinger_cache(memory_ttl);
+
+    let resolve_aturi_cache = new_resolve_aturi_cache(memory_ttl);
+
+    let identity_resolver = IdentityResolver::new(
+        &http_client,
+        &plc_client,
+        dns_resolver,
+        &config.plc_directory,
+    );
+
+    let mut resolver = Resolver::new(
         &http_client,
+        resolve_webhostmeta_cache,
         resolve_webfinger_cache,
         resolve_aturi_cache,
+        host_meta_overrides,
+        identity_resolver,
+        ResolverOptions {
+            webfinger: config.webfinger,
+            host_meta_min_ttl: config.host_meta_min_ttl,
+            host_meta_max_ttl: config.host_meta_max_ttl,
+            resolve_concurrency: config.resolve_concurrency,
+            resolve_deadline: config.resolve_deadline,
+            server_policy,
+        },
+    );
+
+    if let Some(store) = store {
+        resolver = resolver.with_store(store);
+        let (webhostmeta_count, aturi_count) = resolver.load_store().await?;
+        tracing::info!(
+            "Loaded {} host-meta and {} AT-URI cache entries from the cache store",
+            webhostmeta_count,
+            aturi_count
+        );
+    }
+
+    let web_context = WebContext::new(
+        config.external_base.as_str(),
+        AppEngine::from(jinja),
+        &default_servers,
+        resolver,
+        config.admin_token.clone(),
+        metrics.clone(),
     );
 
     let app = build_router(web_context.clone());
@@ -133,6 +221,19 @@ async fn main() -> Result<()> {
         });
     }
 
+    {
+        let inner_token = token.clone();
+        tracker.spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(5));
+            loop {
+                tokio::select! {
+                    () = inner_token.cancelled() => break,
+                    _ = interval.tick() => metrics.run_upkeep(),
+                }
+            }
+        });
+    }
+
     {
         let inner_config = config.clone();
         let http_port = *inner_config.http_port.as_ref();
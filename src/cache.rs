@@ -1,15 +1,40 @@
 use anyhow::{anyhow, Result};
-use moka::{future::Cache, Expiry};
+use futures_util::{stream, Stream, StreamExt};
+use moka::{
+    future::Cache,
+    ops::compute::{CompResult, Op},
+    Expiry,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::{
+    future::Future,
+    collections::BTreeSet,
     hash::Hasher,
-    time::{Duration, Instant},
+    pin::pin,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
 };
 
 use crate::{
+    egress::ServerPolicy,
+    errors::AtUriError,
+    identity::{Identity, IdentityResolver},
     model::AtUri,
-    webhostmeta::{query, WebHostMeta},
+    overrides::HostMetaOverrides,
+    store::{CacheStore, StoreTable},
+    telemetry,
+    webhostmeta::{query, query_webfinger, revalidate, Fetched, Link, WebHostMeta},
 };
 
+/// How long a host-meta document is kept after it stops being fresh. Stale
+/// documents are served while they are refreshed in the background, and kept
+/// when the refresh fails.
+const STALE_RETENTION: Duration = Duration::from_secs(60 * 60 * 24);
+
+/// How long a stale document is treated as fresh while it is refreshed, so
+/// that concurrent requests do not start more refreshes.
+const REFRESH_LEASE: Duration = Duration::from_secs(30);
+
 struct ResolveWebHostMetaExpiry;
 
 struct ResolveAtUriExpiry;
@@ -21,127 +46,1094 @@ impl Expiry<String, ResolveWebHostMetaResult> for ResolveWebHostMetaExpiry {
         value: &ResolveWebHostMetaResult,
         _current_time: Instant,
     ) -> Option<Duration> {
-        match value {
-            ResolveWebHostMetaResult::Found(_) => None,
-            ResolveWebHostMetaResult::NotFound(_) => Some(Duration::from_secs(60 * 10)),
-        }
+        Some(webhostmeta_ttl(value))
+    }
+
+    fn expire_after_update(
+        &self,
+        key: &String,
+        value: &ResolveWebHostMetaResult,
+        current_time: Instant,
+        _current_duration: Option<Duration>,
+    ) -> Option<Duration> {
+        self.expire_after_create(key, value, current_time)
     }
 }
 
-impl Expiry<String, ResolveAtUriResult> for ResolveAtUriExpiry {
+impl Expiry<String, CachedAtUri> for ResolveAtUriExpiry {
     fn expire_after_create(
         &self,
         _key: &String,
-        value: &ResolveAtUriResult,
+        value: &CachedAtUri,
         _current_time: Instant,
     ) -> Option<Duration> {
-        match value {
-            ResolveAtUriResult::Found(_) => Some(Duration::from_secs(60 * 30)),
-            ResolveAtUriResult::NotFound(_) => Some(Duration::from_secs(60 * 10)),
-        }
+        Some(aturi_ttl(&value.result))
     }
 }
 
-#[derive(Clone, PartialEq, Eq)]
+/// Returns how long a host-meta cache entry is kept.
+fn webhostmeta_ttl(value: &ResolveWebHostMetaResult) -> Duration {
+    match value {
+        ResolveWebHostMetaResult::Found(cached) => cached
+            .stale_until
+            .duration_since(SystemTime::now())
+            .unwrap_or_default(),
+        ResolveWebHostMetaResult::NotFound(_) => Duration::from_secs(60 * 10),
+    }
+}
+
+/// Returns how long an AT-URI cache entry is kept.
+fn aturi_ttl(value: &ResolveAtUriResult) -> Duration {
+    match value {
+        ResolveAtUriResult::Found(_) => Duration::from_secs(60 * 30),
+        ResolveAtUriResult::NotFound(_) => Duration::from_secs(60 * 10),
+    }
+}
+
+/// A fetched host-meta document, the time until which it is used without
+/// being revalidated and the time until which it is kept.
+#[derive(Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct CachedWebHostMeta {
+    pub(crate) fetched: Fetched,
+    pub(crate) fresh_until: SystemTime,
+    pub(crate) stale_until: SystemTime,
+}
+
+impl CachedWebHostMeta {
+    fn is_fresh(&self) -> bool {
+        SystemTime::now() < self.fresh_until
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Deserialize, Serialize)]
 pub enum ResolveWebHostMetaResult {
-    Found(WebHostMeta),
+    Found(CachedWebHostMeta),
     NotFound(String),
 }
 
-#[derive(Clone, PartialEq, Eq)]
+/// The destination an AT-URI resolved to, along with the server and link that
+/// produced it.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Resolution {
+    pub destination: String,
+    pub server: String,
+    pub link: Link,
+}
+
+#[derive(Clone, PartialEq, Eq, Deserialize, Serialize)]
 pub enum ResolveAtUriResult {
-    Found(String),
+    Found(Resolution),
     NotFound(String),
 }
 
-pub fn new_resolve_webhostmeta_cache() -> Cache<String, ResolveWebHostMetaResult> {
-    let expiry = ResolveWebHostMetaExpiry;
-    Cache::builder()
-        .max_capacity(1024 * 20)
-        .expire_after(expiry)
-        .build()
+/// An AT-URI cache entry: the AT-URI as given, the servers that were
+/// considered for it and the result. The servers are kept so that entries can
+/// be purged for any of them, including entries that found nothing there.
+#[derive(Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct CachedAtUri {
+    pub aturi: String,
+    pub servers: Vec<String>,
+    pub result: ResolveAtUriResult,
+}
+
+impl CachedAtUri {
+    fn considered(&self, server: &str) -> bool {
+        self.servers.iter().any(|considered| considered == server)
+    }
+}
+
+/// Builds an in-memory cache. When `memory_ttl` is given, entries are kept
+/// for no longer than it, whatever lifetime the expiry gives them.
+fn new_cache<V>(
+    max_capacity: u64,
+    expiry: impl Expiry<String, V> + Send + Sync + 'static,
+    memory_ttl: Option<Duration>,
+) -> Cache<String, V>
+where
+    V: Clone + Send + Sync + 'static,
+{
+    let builder = Cache::builder()
+        .max_capacity(max_capacity)
+        .expire_after(expiry);
+    match memory_ttl {
+        Some(memory_ttl) => builder.time_to_live(memory_ttl).build(),
+        None => builder.build(),
+    }
+}
+
+pub fn new_resolve_webhostmeta_cache(
+    memory_ttl: Option<Duration>,
+) -> Cache<String, ResolveWebHostMetaResult> {
+    new_cache(1024 * 20, ResolveWebHostMetaExpiry, memory_ttl)
+}
+
+/// Returns the cache for WebFinger documents queried for each authority. It
+/// is kept apart from the host-meta cache so that lookups for many
+/// authorities cannot evict host-meta documents.
+pub fn new_resolve_webfinger_cache(
+    memory_ttl: Option<Duration>,
+) -> Cache<String, ResolveWebHostMetaResult> {
+    new_cache(1024 * 4, ResolveWebHostMetaExpiry, memory_ttl)
+}
+
+pub fn new_resolve_aturi_cache(memory_ttl: Option<Duration>) -> Cache<String, CachedAtUri> {
+    new_cache(1024 * 20, ResolveAtUriExpiry, memory_ttl)
 }
 
-pub fn new_resolve_aturi_cache() -> Cache<String, ResolveAtUriResult> {
-    let expiry = ResolveAtUriExpiry;
-    Cache::builder()
-        .max_capacity(1024 * 20)
-        .expire_after(expiry)
-        .build()
+/// Resolution settings that come from configuration.
+#[derive(Clone)]
+pub struct ResolverOptions {
+    /// Whether WebFinger documents are queried for links.
+    pub webfinger: bool,
+    /// The shortest time a host-meta document is used before revalidation,
+    /// whatever its caching headers say.
+    pub host_meta_min_ttl: Duration,
+    /// The longest time a host-meta document is used before revalidation,
+    /// also used when its caching headers say nothing.
+    pub host_meta_max_ttl: Duration,
+    /// How many servers are queried at once while resolving an AT-URI.
+    pub resolve_concurrency: usize,
+    /// How long resolving an AT-URI may take, including every server query.
+    pub resolve_deadline: Duration,
+    /// Which servers may be queried.
+    pub server_policy: ServerPolicy,
 }
 
-pub(crate) async fn webhostmeta_cached(
-    cache: &Cache<String, ResolveWebHostMetaResult>,
-    http_client: &reqwest::Client,
-    hostname: &str,
-) -> Result<WebHostMeta> {
-    if let Some(resolve_handle_result) = cache.get(hostname).await {
-        return match resolve_handle_result {
-            ResolveWebHostMetaResult::Found(webhostmeta) => Ok(webhostmeta),
+impl Default for ResolverOptions {
+    fn default() -> Self {
+        Self {
+            webfinger: false,
+            host_meta_min_ttl: Duration::from_secs(60),
+            host_meta_max_ttl: Duration::from_secs(60 * 60 * 24),
+            resolve_concurrency: 8,
+            resolve_deadline: Duration::from_secs(5),
+            server_policy: ServerPolicy::default(),
+        }
+    }
+}
+
+impl ResolverOptions {
+    /// Returns how long a document stays fresh given the freshness lifetime
+    /// from its caching headers.
+    fn host_meta_ttl(&self, max_age: Option<Duration>) -> Duration {
+        max_age
+            .unwrap_or(self.host_meta_max_ttl)
+            .clamp(self.host_meta_min_ttl, self.host_meta_max_ttl)
+    }
+}
+
+/// The state needed to resolve AT-URIs: the HTTP client used to fetch
+/// host-meta documents, the resolution caches, operator overrides, the
+/// identity resolver and resolution settings.
+#[derive(Clone)]
+pub struct Resolver {
+    http_client: reqwest::Client,
+    webhostmeta_cache: Cache<String, ResolveWebHostMetaResult>,
+    webfinger_cache: Cache<String, ResolveWebHostMetaResult>,
+    aturi_cache: Cache<String, CachedAtUri>,
+    overrides: HostMetaOverrides,
+    identity_resolver: IdentityResolver,
+    options: ResolverOptions,
+    store: Option<Arc<dyn CacheStore>>,
+}
+
+impl Resolver {
+    pub fn new(
+        http_client: &reqwest::Client,
+        webhostmeta_cache: Cache<String, ResolveWebHostMetaResult>,
+        webfinger_cache: Cache<String, ResolveWebHostMetaResult>,
+        aturi_cache: Cache<String, CachedAtUri>,
+        overrides: HostMetaOverrides,
+        identity_resolver: IdentityResolver,
+        options: ResolverOptions,
+    ) -> Self {
+        Self {
+            http_client: http_client.clone(),
+            webhostmeta_cache,
+            webfinger_cache,
+            aturi_cache,
+            overrides,
+            identity_resolver,
+            options,
+            store: None,
+        }
+    }
+
+    /// Keeps cache entries in a store behind the in-memory caches. Entries
+    /// missing from memory are read from the store, and entries are written
+    /// through to it as they are filled. An entry held in memory is used
+    /// without consulting the store, so entries that other instances write to
+    /// a shared store are only seen once the local entry expires. Caches used
+    /// with a shared store should be built with a short `memory_ttl`.
+    pub fn with_store(self, store: Arc<dyn CacheStore>) -> Self {
+        Self {
+            store: Some(store),
+            ..self
+        }
+    }
+
+    /// Fills the caches from the store, returning the number of host-meta and
+    /// AT-URI entries loaded. Loaded AT-URI entries are kept for their full
+    /// lifetime again, as the cache does not track when they were created.
+    /// WebFinger entries are only read from the store on demand.
+    pub async fn load_store(&self) -> Result<(usize, usize)> {
+        let Some(store) = self.store.as_ref() else {
+            return Ok((0, 0));
+        };
+
+        let mut webhostmeta_count = 0;
+        for (key, value) in store.load(StoreTable::WebHostMeta).await? {
+            if let Some(value) = decode_stored::<ResolveWebHostMetaResult>(&key, &value) {
+                self.webhostmeta_cache.insert(key, value).await;
+                webhostmeta_count += 1;
+            }
+        }
+
+        let mut aturi_count = 0;
+        for (key, value) in store.load(StoreTable::AtUri).await? {
+            if let Some(value) = decode_stored::<CachedAtUri>(&key, &value) {
+                self.aturi_cache.insert(key, value).await;
+                aturi_count += 1;
+            }
+        }
+
+        Ok((webhostmeta_count, aturi_count))
+    }
+
+    /// Returns the entry for the key from the store, if there is one.
+    async fn stored<V: DeserializeOwned>(&self, table: StoreTable, cache_key: &str) -> Option<V> {
+        let store = self.store.as_ref()?;
+        match store.get(table, cache_key).await {
+            Ok(value) => decode_stored(cache_key, &value?),
+            Err(err) => {
+                tracing::warn!(error = ?err, cache_key, "reading cache store failed");
+                None
+            }
+        }
+    }
+
+    /// Writes an entry to the store in the background.
+    fn store<V: Serialize>(&self, table: StoreTable, cache_key: &str, value: &V, ttl: Duration) {
+        let Some(store) = self.store.clone() else {
+            return;
+        };
+        let value = match serde_json::to_vec(value) {
+            Ok(value) => value,
+            Err(err) => {
+                tracing::warn!(error = ?err, cache_key, "serializing cache entry failed");
+                return;
+            }
+        };
+        let cache_key = cache_key.to_string();
+        tokio::spawn(async move {
+            if let Err(err) = store.insert(table, &cache_key, value, ttl).await {
+                tracing::warn!(error = ?err, cache_key, "writing cache store failed");
+            }
+        });
+    }
+
+    fn store_document(&self, table: StoreTable, cache_key: &str, value: &ResolveWebHostMetaResult) {
+        self.store(table, cache_key, value, webhostmeta_ttl(value));
+    }
+
+    /// Returns the in-memory cache for a table of documents.
+    fn document_cache(&self, table: StoreTable) -> &Cache<String, ResolveWebHostMetaResult> {
+        match table {
+            StoreTable::WebFinger => &self.webfinger_cache,
+            _ => &self.webhostmeta_cache,
+        }
+    }
+
+    fn store_aturi(&self, cache_key: &str, value: &CachedAtUri) {
+        self.store(
+            StoreTable::AtUri,
+            cache_key,
+            value,
+            aturi_ttl(&value.result),
+        );
+    }
+
+    pub(crate) async fn webhostmeta_cached(&self, hostname: &str) -> Result<WebHostMeta> {
+        if let Some(webhostmeta) = self.overrides.replacement(hostname) {
+            return Ok(webhostmeta.clone());
+        }
+
+        self.webhostmeta_fetch_cached(hostname)
+            .await
+            .or_else(|err| self.overrides.fallback(hostname).cloned().ok_or(err))
+    }
+
+    async fn webhostmeta_fetch_cached(&self, hostname: &str) -> Result<WebHostMeta> {
+        let fetch = async {
+            let fetched = query(&self.http_client, hostname).await;
+            match fetched {
+                Err(err) if self.options.webfinger => {
+                    tracing::debug!(error = ?err, hostname, "host-meta query failed");
+                    let resource = format!("acct:{}", hostname);
+                    query_webfinger(&self.http_client, hostname, &resource).await
+                }
+                fetched => fetched,
+            }
+        };
+        self.document_cached(StoreTable::WebHostMeta, hostname.to_string(), fetch)
+            .await
+    }
+
+    /// Returns the cached document for the key, or fetches it when nothing is
+    /// cached. Concurrent misses for the same key share a single fetch. A
+    /// document that is no longer fresh is returned as-is while it is
+    /// revalidated in the background.
+    async fn document_cached(
+        &self,
+        table: StoreTable,
+        cache_key: String,
+        fetch: impl Future<Output = Result<Fetched>>,
+    ) -> Result<WebHostMeta> {
+        let entry = self
+            .document_cache(table)
+            .entry_by_ref(&cache_key)
+            .or_insert_with(async {
+                if let Some(cache_value) = self.stored(table, &cache_key).await {
+                    return cache_value;
+                }
+
+                let cache_value = match fetch.await {
+                    Ok(fetched) => ResolveWebHostMetaResult::Found(self.new_cached(&fetched)),
+                    Err(err) => ResolveWebHostMetaResult::NotFound(err.to_string()),
+                };
+                self.store_document(table, &cache_key, &cache_value);
+                cache_value
+            })
+            .await;
+        telemetry::record_cache_lookup(cache_label(table), !entry.is_fresh());
+
+        match entry.into_value() {
+            ResolveWebHostMetaResult::Found(cached) => {
+                if !cached.is_fresh() {
+                    self.refresh_in_background(table, cache_key).await;
+                }
+                Ok(cached.fetched.webhostmeta)
+            }
             ResolveWebHostMetaResult::NotFound(err) => Err(anyhow!(err)),
+        }
+    }
+
+    /// Revalidates a stale document in a background task. The document is
+    /// leased as fresh for a short time so that only one refresh runs at once,
+    /// and kept as-is if the refresh fails.
+    async fn refresh_in_background(&self, table: StoreTable, cache_key: String) {
+        let leased = self
+            .document_cache(table)
+            .entry_by_ref(&cache_key)
+            .and_compute_with(|entry| async {
+                match entry.map(|entry| entry.into_value()) {
+                    Some(ResolveWebHostMetaResult::Found(cached)) if !cached.is_fresh() => {
+                        Op::Put(ResolveWebHostMetaResult::Found(CachedWebHostMeta {
+                            fresh_until: SystemTime::now() + REFRESH_LEASE,
+                            ..cached
+                        }))
+                    }
+                    _ => Op::Nop,
+                }
+            })
+            .await;
+
+        // Another request already holds the lease, or the entry is gone.
+        let CompResult::ReplacedWith(entry) = leased else {
+            return;
         };
+        let ResolveWebHostMetaResult::Found(cached) = entry.into_value() else {
+            return;
+        };
+
+        let resolver = self.clone();
+        tokio::spawn(async move {
+            match revalidate(&resolver.http_client, &cached.fetched).await {
+                Ok(fetched) => {
+                    let cache_value =
+                        ResolveWebHostMetaResult::Found(resolver.new_cached(&fetched));
+                    resolver.store_document(table, &cache_key, &cache_value);
+                    resolver
+                        .document_cache(table)
+                        .insert(cache_key, cache_value)
+                        .await;
+                }
+                Err(err) => {
+                    tracing::debug!(error = ?err, cache_key, "host-meta refresh failed");
+                }
+            }
+        });
     }
-    let webfinger = query(http_client, hostname).await;
 
-    let cache_value = match webfinger.as_ref() {
-        Ok(webfinger) => ResolveWebHostMetaResult::Found(webfinger.clone()),
-        Err(err) => ResolveWebHostMetaResult::NotFound(err.to_string()),
-    };
+    fn new_cached(&self, fetched: &Fetched) -> CachedWebHostMeta {
+        let fresh_until = SystemTime::now() + self.options.host_meta_ttl(fetched.max_age);
+        CachedWebHostMeta {
+            fetched: fetched.clone(),
+            fresh_until,
+            stale_until: fresh_until + STALE_RETENTION,
+        }
+    }
+
+    #[tracing::instrument(skip_all, fields(aturi = aturi_input, server, outcome, latency_ms))]
+    pub(crate) async fn aturi_cached(
+        &self,
+        servers: &[String],
+        aturi_input: &str,
+        aturi: &AtUri,
+    ) -> Result<Resolution> {
+        let mut hasher = cityhasher::CityHasher::new();
+        hasher.write(aturi_input.as_bytes());
+        for server in servers {
+            hasher.write(server.as_bytes());
+        }
+        let cache_key = hasher.finish().to_string();
+
+        let started = tokio::time::Instant::now();
+        let deadline = started + self.options.resolve_deadline;
+        let entry = self
+            .aturi_cache
+            .entry_by_ref(&cache_key)
+            .or_try_insert_with(async {
+                if let Some(cache_value) = self.stored(StoreTable::AtUri, &cache_key).await {
+                    return Ok(cache_value);
+                }
+
+                let search = async {
+                    let mut identity = IdentityLookup::new(&self.identity_resolver, aturi);
+                    let mut documents = pin!(self.documents_in_order(servers, aturi));
+
+                    while let Some((server, documents)) = documents.next().await {
+                        let Some(resolution) =
+                            match_server(server, aturi, &documents, &mut identity).await
+                        else {
+                            tracing::debug!(server, "no destination found");
+                            continue;
+                        };
+                        return ResolveAtUriResult::Found(resolution);
+                    }
+
+                    ResolveAtUriResult::NotFound(AtUriError::Unsupported.to_string())
+                };
+
+                // Running out of time is not cached, unlike not finding a
+                // destination.
+                let result = tokio::time::timeout_at(deadline, search)
+                    .await
+                    .map_err(|_| AtUriError::Timeout)?;
+                let cache_value = CachedAtUri {
+                    aturi: aturi_input.to_string(),
+                    servers: servers.to_vec(),
+                    result,
+                };
+                self.store_aturi(&cache_key, &cache_value);
+                Ok(cache_value)
+            })
+            .await;
+        if let Ok(entry) = &entry {
+            telemetry::record_cache_lookup(telemetry::CACHE_ATURI, !entry.is_fresh());
+        }
+
+        let resolution = match entry.map(|entry| entry.into_value().result) {
+            Ok(ResolveAtUriResult::Found(resolution)) => Ok(resolution),
+            Ok(ResolveAtUriResult::NotFound(err)) => Err(anyhow!(err)),
+            Err(err) => Err(anyhow!(AtUriError::clone(&err))),
+        };
+
+        let (outcome, server) = match &resolution {
+            Ok(resolution) => ("found", Some(resolution.server.as_str())),
+            Err(err) if matches!(err.downcast_ref(), Some(AtUriError::Timeout)) => {
+                ("timeout", None)
+            }
+            Err(_) => ("not-found", None),
+        };
+        let latency = started.elapsed();
+        telemetry::record_resolution(outcome, server, latency);
 
-    cache.insert(hostname.to_string(), cache_value).await;
-    webfinger
+        let span = tracing::Span::current();
+        if let Some(server) = server {
+            span.record("server", server);
+        }
+        span.record("outcome", outcome);
+        span.record("latency_ms", latency.as_millis() as u64);
+        tracing::debug!("resolved AT-URI");
+
+        resolution
+    }
+
+    /// Evaluates every server and returns each destination that matches the
+    /// AT-URI, in server order. Servers that have not been evaluated when the
+    /// resolve deadline passes are left out.
+    pub(crate) async fn aturi_candidates(
+        &self,
+        servers: &[String],
+        aturi: &AtUri,
+    ) -> Vec<Resolution> {
+        let deadline = tokio::time::Instant::now() + self.options.resolve_deadline;
+        let mut candidates = Vec::new();
+
+        let search = async {
+            let mut identity = IdentityLookup::new(&self.identity_resolver, aturi);
+            let mut documents = pin!(self.documents_in_order(servers, aturi));
+
+            while let Some((server, documents)) = documents.next().await {
+                if let Some(resolution) =
+                    match_server(server, aturi, &documents, &mut identity).await
+                {
+                    candidates.push(resolution);
+                }
+            }
+        };
+
+        if tokio::time::timeout_at(deadline, search).await.is_err() {
+            tracing::debug!("resolve deadline passed");
+        }
+
+        candidates
+    }
+
+    /// Fetches the documents of every server concurrently, yielding them in
+    /// server order. At most `resolve_concurrency` servers are fetched at once.
+    fn documents_in_order<'a>(
+        &'a self,
+        servers: &'a [String],
+        aturi: &'a AtUri,
+    ) -> impl Stream<Item = (&'a str, Vec<WebHostMeta>)> + 'a {
+        stream::iter(servers)
+            .map(move |server| async move {
+                (server.as_str(), self.server_documents(server, aturi).await)
+            })
+            .buffered(self.options.resolve_concurrency)
+    }
+
+    /// Returns the documents a server's links for the AT-URI are taken from,
+    /// in order of preference. When WebFinger is enabled, links the server
+    /// publishes for the authority itself are preferred over the server's
+    /// host-meta links, except for servers whose host-meta is replaced by an
+    /// override. Servers the policy does not allow have no documents.
+    async fn server_documents(&self, server: &str, aturi: &AtUri) -> Vec<WebHostMeta> {
+        let mut documents = Vec::new();
+        if !self.options.server_policy.permits(server) {
+            tracing::debug!(server, "server is not allowed");
+            return documents;
+        }
+        if self.options.webfinger && self.overrides.replacement(server).is_none() {
+            let resource = format!("at://{}", aturi.authority);
+            match self.webfinger_cached(server, &resource).await {
+                Ok(webfinger) => documents.push(webfinger),
+                Err(err) => tracing::debug!(error = ?err, server, "webfinger query failed"),
+            }
+        }
+        match self.webhostmeta_cached(server).await {
+            Ok(webhostmeta) => documents.push(webhostmeta),
+            Err(err) => tracing::debug!(error = ?err, "error encountered"),
+        }
+        documents
+    }
+
+    /// Fetches the WebFinger document for a resource from a server. Results
+    /// are kept in the WebFinger cache, keyed by the WebFinger query.
+    async fn webfinger_cached(&self, server: &str, resource: &str) -> Result<WebHostMeta> {
+        let cache_key = format!("{}/.well-known/webfinger?resource={}", server, resource);
+        let fetch = query_webfinger(&self.http_client, server, resource);
+        self.document_cached(StoreTable::WebFinger, cache_key, fetch)
+            .await
+    }
+
+    /// Records the number of entries held in each cache.
+    pub(crate) async fn record_cache_entries(&self) {
+        self.webhostmeta_cache.run_pending_tasks().await;
+        self.webfinger_cache.run_pending_tasks().await;
+        self.aturi_cache.run_pending_tasks().await;
+        telemetry::record_cache_entries(
+            telemetry::CACHE_WEBHOSTMETA,
+            self.webhostmeta_cache.entry_count(),
+        );
+        telemetry::record_cache_entries(
+            telemetry::CACHE_WEBFINGER,
+            self.webfinger_cache.entry_count(),
+        );
+        telemetry::record_cache_entries(telemetry::CACHE_ATURI, self.aturi_cache.entry_count());
+    }
+
+    /// Returns every host-meta cache entry held in memory, sorted by key.
+    pub(crate) fn webhostmeta_entries(&self) -> Vec<(String, ResolveWebHostMetaResult)> {
+        let mut entries = self
+            .webhostmeta_cache
+            .iter()
+            .map(|(key, value)| (key.to_string(), value))
+            .collect::<Vec<_>>();
+        entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+        entries
+    }
+
+    /// Returns every AT-URI cache entry held in memory, sorted by key.
+    pub(crate) fn aturi_entries(&self) -> Vec<(String, CachedAtUri)> {
+        let mut entries = self
+            .aturi_cache
+            .iter()
+            .map(|(key, value)| (key.to_string(), value))
+            .collect::<Vec<_>>();
+        entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+        entries
+    }
+
+    /// Returns the host-meta cache entry for a key from memory or the store,
+    /// without fetching or refreshing it.
+    pub(crate) async fn webhostmeta_entry(
+        &self,
+        cache_key: &str,
+    ) -> Option<ResolveWebHostMetaResult> {
+        match self.webhostmeta_cache.get(cache_key).await {
+            Some(cache_value) => Some(cache_value),
+            None => self.stored(StoreTable::WebHostMeta, cache_key).await,
+        }
+    }
+
+    /// Removes everything cached for a hostname: its host-meta document, the
+    /// WebFinger documents queried from it and the AT-URI resolutions that
+    /// considered it. Entries are found in memory and in the store. Returns the
+    /// number of entries removed.
+    pub(crate) async fn purge_hostname(&self, hostname: &str) -> Result<usize> {
+        let webfinger_prefix = format!("{}/.well-known/webfinger?", hostname);
+        let mut webfinger_keys = self
+            .webfinger_cache
+            .iter()
+            .map(|(key, _)| key.to_string())
+            .filter(|key| key.starts_with(&webfinger_prefix))
+            .collect::<BTreeSet<_>>();
+        let mut aturi_keys = self
+            .aturi_cache
+            .iter()
+            .filter(|(_, value)| value.considered(hostname))
+            .map(|(key, _)| key.to_string())
+            .collect::<BTreeSet<_>>();
+
+        let mut webhostmeta_cached = self.webhostmeta_cache.contains_key(hostname);
+        if let Some(store) = self.store.as_ref() {
+            webhostmeta_cached |= store
+                .get(StoreTable::WebHostMeta, hostname)
+                .await?
+                .is_some();
+            webfinger_keys.extend(store.keys(StoreTable::WebFinger, &webfinger_prefix).await?);
+            for cache_key in store.keys(StoreTable::AtUri, "").await? {
+                if aturi_keys.contains(&cache_key) {
+                    continue;
+                }
+                let stored = self
+                    .stored::<CachedAtUri>(StoreTable::AtUri, &cache_key)
+                    .await;
+                if stored.is_some_and(|value| value.considered(hostname)) {
+                    aturi_keys.insert(cache_key);
+                }
+            }
+        }
+
+        self.purge(StoreTable::WebHostMeta, hostname).await?;
+        for cache_key in &webfinger_keys {
+            self.purge(StoreTable::WebFinger, cache_key).await?;
+        }
+        for cache_key in &aturi_keys {
+            self.purge(StoreTable::AtUri, cache_key).await?;
+        }
+        Ok(usize::from(webhostmeta_cached) + webfinger_keys.len() + aturi_keys.len())
+    }
+
+    /// Removes an AT-URI cache entry, returning whether it was held in memory.
+    pub(crate) async fn purge_aturi(&self, cache_key: &str) -> Result<bool> {
+        self.purge(StoreTable::AtUri, cache_key).await
+    }
+
+    /// Removes an entry from memory and from the store, returning whether it
+    /// was held in memory.
+    async fn purge(&self, table: StoreTable, cache_key: &str) -> Result<bool> {
+        let removed = match table {
+            StoreTable::WebHostMeta | StoreTable::WebFinger => {
+                self.document_cache(table).remove(cache_key).await.is_some()
+            }
+            StoreTable::AtUri => self.aturi_cache.remove(cache_key).await.is_some(),
+        };
+        if let Some(store) = self.store.as_ref() {
+            store.remove(table, cache_key).await?;
+        }
+        Ok(removed)
+    }
+}
+
+/// Returns the metrics label for a table of documents.
+fn cache_label(table: StoreTable) -> &'static str {
+    match table {
+        StoreTable::WebFinger => telemetry::CACHE_WEBFINGER,
+        _ => telemetry::CACHE_WEBHOSTMETA,
+    }
 }
 
-pub(crate) async fn aturi_cached(
-    http_client: &reqwest::Client,
-    webfinger_cache: &Cache<String, ResolveWebHostMetaResult>,
-    aturi_cache: &Cache<String, ResolveAtUriResult>,
-    servers: &Vec<String>,
-    aturi_input: &str,
+/// Deserializes an entry read from the store. Unreadable entries, such as
+/// ones written by an incompatible version, are ignored.
+fn decode_stored<V: DeserializeOwned>(cache_key: &str, value: &[u8]) -> Option<V> {
+    serde_json::from_slice(value)
+        .inspect_err(|err| tracing::debug!(error = ?err, cache_key, "ignoring cache entry"))
+        .ok()
+}
+
+/// Returns the destination for the AT-URI from the first of a server's
+/// documents that has a matching link.
+async fn match_server(
+    server: &str,
     aturi: &AtUri,
-) -> Result<String> {
-    let mut hasher = cityhasher::CityHasher::new();
-    hasher.write(aturi_input.as_bytes());
-    for server in servers {
-        hasher.write(server.as_bytes());
-    }
-    let cache_key = hasher.finish().to_string();
-
-    if let Some(resolve_handle_result) = aturi_cache.get(&cache_key).await {
-        return match resolve_handle_result {
-            ResolveAtUriResult::Found(destination) => Ok(destination),
-            ResolveAtUriResult::NotFound(err) => Err(anyhow!(err)),
-        };
+    documents: &[WebHostMeta],
+    identity: &mut IdentityLookup<'_>,
+) -> Option<Resolution> {
+    for document in documents {
+        let identity = identity.get(document).await;
+        if let Some((destination, link)) = document.match_uri(server, aturi, identity) {
+            return Some(Resolution {
+                destination,
+                server: server.to_string(),
+                link: link.clone(),
+            });
+        }
+    }
+    None
+}
+
+/// Resolves the identity behind an AT-URI at most once, and only when a
+/// host-meta document has links that need it.
+struct IdentityLookup<'a> {
+    identity_resolver: &'a IdentityResolver,
+    authority: &'a str,
+    identity: Identity,
+    resolved: bool,
+}
+
+impl<'a> IdentityLookup<'a> {
+    fn new(identity_resolver: &'a IdentityResolver, aturi: &'a AtUri) -> Self {
+        Self {
+            identity_resolver,
+            authority: &aturi.authority,
+            identity: Identity::from_authority(&aturi.authority),
+            resolved: false,
+        }
+    }
+
+    async fn get(&mut self, webhostmeta: &WebHostMeta) -> &Identity {
+        if !self.resolved && webhostmeta.needs_identity() {
+            self.identity = self.identity_resolver.resolve(self.authority).await;
+            self.resolved = true;
+        }
+        &self.identity
     }
+}
 
-    for server in servers {
-        let webfinger = webhostmeta_cached(webfinger_cache, http_client, server).await;
+#[cfg(test)]
+pub(crate) mod tests {
+    use anyhow::{anyhow, Result};
+    use async_trait::async_trait;
+    use axum::http::{header::CONTENT_TYPE, StatusCode};
+    use futures_util::future::join_all;
+    use hickory_resolver::{
+        config::ResolverConfig, name_server::TokioConnectionProvider, TokioResolver,
+    };
+    use std::{
+        collections::HashMap,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+        time::{Duration, SystemTime},
+    };
 
-        if let Err(err) = webfinger {
-            tracing::debug!(error = ?err, "error encountered");
-            continue;
+    use super::{
+        new_resolve_aturi_cache, new_resolve_webfinger_cache, new_resolve_webhostmeta_cache,
+        CachedWebHostMeta, ResolveWebHostMetaResult, Resolver, ResolverOptions,
+    };
+    use crate::{
+        errors::AtUriError,
+        identity::IdentityResolver,
+        model::validate_aturi,
+        overrides::HostMetaOverrides,
+        store::{CacheStore, StoreTable},
+        webhostmeta::{Fetched, Format, WebHostMeta},
+    };
+
+    /// Returns a resolver whose HTTP client may reach local addresses.
+    pub(crate) fn test_resolver(options: ResolverOptions) -> Resolver {
+        let http_client = reqwest::Client::new();
+        let dns_resolver = TokioResolver::builder_with_config(
+            ResolverConfig::default(),
+            TokioConnectionProvider::default(),
+        )
+        .build();
+        let identity_resolver = IdentityResolver::new(
+            &http_client,
+            &http_client,
+            dns_resolver,
+            "https://plc.directory",
+        );
+        Resolver::new(
+            &http_client,
+            new_resolve_webhostmeta_cache(None),
+            new_resolve_webfinger_cache(None),
+            new_resolve_aturi_cache(None),
+            HostMetaOverrides::default(),
+            identity_resolver,
+            options,
+        )
+    }
+
+    /// Returns a host-meta document with a single link to the template.
+    fn document(template: &str) -> WebHostMeta {
+        serde_json::from_value(serde_json::json!({
+            "links": [{"rel": "https://hopper.at/rel/link", "template": template}]
+        }))
+        .unwrap()
+    }
+
+    fn fetched(url: &str, webhostmeta: WebHostMeta) -> Fetched {
+        Fetched {
+            webhostmeta,
+            url: url.to_string(),
+            format: Format::Json,
+            max_age: None,
+            etag: None,
+            last_modified: None,
         }
+    }
+
+    /// Serves a host-meta document on a local port, counting requests. Each
+    /// response is delayed so that concurrent lookups overlap with it.
+    async fn serve(status: StatusCode, body: String, requests: Arc<AtomicUsize>) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!(
+            "http://{}/.well-known/host-meta.json",
+            listener.local_addr().unwrap()
+        );
+        let app = axum::Router::new().route(
+            "/.well-known/host-meta.json",
+            axum::routing::get(move || {
+                let requests = requests.clone();
+                let body = body.clone();
+                async move {
+                    requests.fetch_add(1, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    (status, [(CONTENT_TYPE, "application/jrd+json")], body)
+                }
+            }),
+        );
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        url
+    }
+
+    #[tokio::test]
+    async fn test_stale_document_refreshed_in_background() {
+        let stale = document("https://a.example/stale/{authority}");
+        let refreshed = document("https://a.example/refreshed/{authority}");
 
-        let webfinger = webfinger.unwrap();
+        for (status, expected) in [
+            (StatusCode::OK, refreshed.clone()),
+            (StatusCode::INTERNAL_SERVER_ERROR, stale.clone()),
+        ] {
+            let requests = Arc::new(AtomicUsize::new(0));
+            let body = serde_json::to_string(&refreshed).unwrap();
+            let url = serve(status, body, requests.clone()).await;
 
-        let destination = webfinger.match_uri(server, aturi);
-        if destination.is_none() {
-            tracing::debug!("no destination found");
-            continue;
+            let resolver = test_resolver(ResolverOptions::default());
+            let now = SystemTime::now();
+            let cached = ResolveWebHostMetaResult::Found(CachedWebHostMeta {
+                fetched: fetched(&url, stale.clone()),
+                fresh_until: now - Duration::from_secs(1),
+                stale_until: now + Duration::from_secs(60),
+            });
+            resolver
+                .webhostmeta_cache
+                .insert("a.example".to_string(), cached)
+                .await;
+
+            // Every lookup gets the stale document at once, and only one of
+            // them starts a refresh.
+            let lookups = (0..8).map(|_| {
+                resolver.document_cached(StoreTable::WebHostMeta, "a.example".to_string(), async {
+                    Err(anyhow!("cached documents are not fetched"))
+                })
+            });
+            for webhostmeta in join_all(lookups).await {
+                assert_eq!(webhostmeta.unwrap(), stale);
+            }
+
+            // Let the refresh finish. A failed refresh keeps the stale
+            // document.
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            assert_eq!(requests.load(Ordering::SeqCst), 1);
+            match resolver.webhostmeta_cache.get("a.example").await {
+                Some(ResolveWebHostMetaResult::Found(cached)) => {
+                    assert_eq!(cached.fetched.webhostmeta, expected)
+                }
+                _ => panic!("document is no longer cached"),
+            }
         }
+    }
 
-        let destination = destination.unwrap();
+    #[tokio::test]
+    async fn test_concurrent_misses_share_fetch() {
+        let resolver = test_resolver(ResolverOptions::default());
+        let webhostmeta = document("https://a.example/{authority}");
 
-        aturi_cache
-            .insert(cache_key, ResolveAtUriResult::Found(destination.clone()))
-            .await;
-        return Ok(destination);
+        for (cache_key, found) in [("a.example", true), ("b.example", false)] {
+            let fetches = Arc::new(AtomicUsize::new(0));
+            let lookups = (0..8).map(|_| {
+                let fetches = fetches.clone();
+                let webhostmeta = webhostmeta.clone();
+                let fetch = async move {
+                    fetches.fetch_add(1, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    match found {
+                        true => Ok(fetched("https://a.example/", webhostmeta)),
+                        false => Err(anyhow!("not found")),
+                    }
+                };
+                resolver.document_cached(StoreTable::WebHostMeta, cache_key.to_string(), fetch)
+            });
+
+            for result in join_all(lookups).await {
+                match found {
+                    true => assert_eq!(result.unwrap(), webhostmeta),
+                    false => assert!(result.is_err()),
+                }
+            }
+            assert_eq!(fetches.load(Ordering::SeqCst), 1, "{}", cache_key);
+        }
     }
 
-    let err = anyhow!("error-web-unsupported-aturi Unsupported AT-URI");
-    aturi_cache
-        .insert(cache_key, ResolveAtUriResult::NotFound(err.to_string()))
-        .await;
+    #[tokio::test]
+    async fn test_memory_ttl() {
+        let now = SystemTime::now();
+        let cached = ResolveWebHostMetaResult::Found(CachedWebHostMeta {
+            fetched: fetched(
+                "https://a.example/",
+                document("https://a.example/{authority}"),
+            ),
+            fresh_until: now + Duration::from_secs(60),
+            stale_until: now + Duration::from_secs(120),
+        });
 
-    Err(err)
+        let bounded = new_resolve_webhostmeta_cache(Some(Duration::from_millis(100)));
+        let unbounded = new_resolve_webhostmeta_cache(None);
+        for cache in [&bounded, &unbounded] {
+            cache.insert("a.example".to_string(), cached.clone()).await;
+        }
+        tokio::time::sleep(Duration::from_millis(300)).await;
+
+        assert!(bounded.get("a.example").await.is_none());
+        assert!(unbounded.get("a.example").await.is_some());
+    }
+
+    /// A store that holds a host-meta document for each server and takes a
+    /// given time to return it, so that servers finish in a chosen order.
+    #[derive(Default)]
+    struct DelayedStore {
+        documents: HashMap<String, (Duration, Vec<u8>)>,
+        inserted: Mutex<Vec<(StoreTable, String)>>,
+    }
+
+    impl DelayedStore {
+        fn with_document(mut self, server: &str, delay: Duration) -> Self {
+            let now = SystemTime::now();
+            let template = format!("https://{}/{{authority}}/{{rkey}}", server);
+            let cached = ResolveWebHostMetaResult::Found(CachedWebHostMeta {
+                fetched: fetched(&format!("https://{}/", server), document(&template)),
+                fresh_until: now + Duration::from_secs(60),
+                stale_until: now + Duration::from_secs(120),
+            });
+            let value = serde_json::to_vec(&cached).unwrap();
+            self.documents.insert(server.to_string(), (delay, value));
+            self
+        }
+    }
+
+    #[async_trait]
+    impl CacheStore for DelayedStore {
+        async fn get(&self, table: StoreTable, key: &str) -> Result<Option<Vec<u8>>> {
+            match (table, self.documents.get(key)) {
+                (StoreTable::WebHostMeta, Some((delay, value))) => {
+                    tokio::time::sleep(*delay).await;
+                    Ok(Some(value.clone()))
+                }
+                _ => Ok(None),
+            }
+        }
+
+        async fn insert(
+            &self,
+            table: StoreTable,
+            key: &str,
+            _value: Vec<u8>,
+            _ttl: Duration,
+        ) -> Result<()> {
+            self.inserted.lock().unwrap().push((table, key.to_string()));
+            Ok(())
+        }
+
+        async fn remove(&self, _table: StoreTable, _key: &str) -> Result<()> {
+            Ok(())
+        }
+
+        async fn keys(&self, _table: StoreTable, _prefix: &str) -> Result<Vec<String>> {
+            Ok(Vec::new())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_aturi_resolved_in_server_order() {
+        let store = DelayedStore::default()
+            .with_document("a.example", Duration::from_millis(200))
+            .with_document("b.example", Duration::ZERO);
+        let resolver = test_resolver(ResolverOptions::default()).with_store(Arc::new(store));
+
+        let servers = vec!["a.example".to_string(), "b.example".to_string()];
+        let input = "at://alice.example.com/app.bsky.feed.post/abc123";
+        let aturi = validate_aturi(input).unwrap();
+
+        // b.example answers first, but a.example comes first in the list.
+        let resolution = resolver
+            .aturi_cached(&servers, input, &aturi)
+            .await
+            .unwrap();
+        assert_eq!(resolution.server, "a.example");
+        assert_eq!(
+            resolution.destination,
+            "https://a.example/alice.example.com/abc123"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_aturi_timeout_not_cached() {
+        let store = Arc::new(
+            DelayedStore::default()
+                .with_document("a.example", Duration::from_secs(2))
+                .with_document("b.example", Duration::ZERO),
+        );
+        let resolver = test_resolver(ResolverOptions {
+            resolve_deadline: Duration::from_millis(200),
+            ..ResolverOptions::default()
+        })
+        .with_store(store.clone());
+
+        let servers = vec!["a.example".to_string(), "b.example".to_string()];
+        let input = "at://alice.example.com/app.bsky.feed.post/abc123";
+        let aturi = validate_aturi(input).unwrap();
+
+        let err = resolver
+            .aturi_cached(&servers, input, &aturi)
+            .await
+            .unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(AtUriError::Timeout)));
+
+        resolver.aturi_cache.run_pending_tasks().await;
+        assert_eq!(resolver.aturi_cache.entry_count(), 0);
+        assert!(store
+            .inserted
+            .lock()
+            .unwrap()
+            .iter()
+            .all(|(table, _)| *table != StoreTable::AtUri));
+    }
 }
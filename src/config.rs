@@ -1,4 +1,7 @@
 use anyhow::{anyhow, Result};
+use std::time::Duration;
+
+use crate::model::is_valid_server;
 
 #[derive(Clone)]
 pub struct HttpPort(u16);
@@ -6,6 +9,43 @@ pub struct HttpPort(u16);
 #[derive(Clone)]
 pub struct CertificateBundles(Vec<String>);
 
+#[derive(Clone)]
+pub struct DefaultServers(Vec<String>);
+
+/// Controls how entries in the host-meta overrides file are used.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OverrideMode {
+    /// Always use the override instead of fetching the remote host-meta.
+    #[default]
+    Override,
+    /// Use the override only when fetching the remote host-meta fails.
+    Fallback,
+}
+
+/// Where host-meta overrides are loaded from.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum OverrideSource {
+    /// The overrides bundled with Hopper.
+    #[default]
+    Bundled,
+    /// A JSON file given by the operator.
+    File(String),
+    /// No overrides.
+    Disabled,
+}
+
+/// Selects how log lines are written.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Multi-line, human readable output.
+    #[default]
+    Pretty,
+    /// Single-line, human readable output.
+    Compact,
+    /// One JSON object per line, including the fields of enclosing spans.
+    Json,
+}
+
 #[derive(Clone)]
 pub struct Config {
     pub version: String,
@@ -13,6 +53,22 @@ pub struct Config {
     pub external_base: String,
     pub certificate_bundles: CertificateBundles,
     pub user_agent: String,
+    pub default_servers: DefaultServers,
+    pub append_default_servers: bool,
+    pub host_meta_overrides: OverrideSource,
+    pub host_meta_overrides_mode: OverrideMode,
+    pub plc_directory: String,
+    pub webfinger: bool,
+    pub host_meta_min_ttl: Duration,
+    pub host_meta_max_ttl: Duration,
+    pub resolve_concurrency: usize,
+    pub resolve_deadline: Duration,
+    pub cache_store: Option<String>,
+    pub admin_token: Option<String>,
+    pub log_format: LogFormat,
+    pub server_allow_list: Vec<String>,
+    pub server_deny_list: Vec<String>,
+    pub allow_private_addresses: bool,
 }
 
 impl Config {
@@ -27,12 +83,83 @@ impl Config {
 
         let user_agent = default_env("USER_AGENT", &default_user_agent);
 
+        let default_servers: DefaultServers = default_env(
+            "DEFAULT_SERVERS",
+            "smokesignal.events,frontpage.fyi,whtwnd.com,bsky.app",
+        )
+        .try_into()?;
+
+        let append_default_servers = parse_bool(
+            "APPEND_DEFAULT_SERVERS",
+            &default_env("APPEND_DEFAULT_SERVERS", "true"),
+        )?;
+
+        let host_meta_overrides: OverrideSource = optional_env("HOST_META_OVERRIDES").into();
+
+        let host_meta_overrides_mode: OverrideMode =
+            default_env("HOST_META_OVERRIDES_MODE", "override").try_into()?;
+
+        let plc_directory = default_env("PLC_DIRECTORY", "https://plc.directory");
+
+        let webfinger = parse_bool("WEBFINGER", &default_env("WEBFINGER", "false"))?;
+
+        let host_meta_min_ttl =
+            parse_seconds("HOST_META_MIN_TTL", &default_env("HOST_META_MIN_TTL", "60"))?;
+        let host_meta_max_ttl = parse_seconds(
+            "HOST_META_MAX_TTL",
+            &default_env("HOST_META_MAX_TTL", "86400"),
+        )?;
+        if host_meta_min_ttl > host_meta_max_ttl {
+            return Err(anyhow!(
+                "HOST_META_MIN_TTL must not be greater than HOST_META_MAX_TTL"
+            ));
+        }
+
+        let resolve_concurrency = default_env("RESOLVE_CONCURRENCY", "8")
+            .parse::<usize>()
+            .ok()
+            .filter(|value| *value > 0)
+            .ok_or(anyhow!("RESOLVE_CONCURRENCY must be a positive number"))?;
+
+        let resolve_deadline =
+            parse_seconds("RESOLVE_DEADLINE", &default_env("RESOLVE_DEADLINE", "5"))?;
+
+        let cache_store = Some(optional_env("CACHE_STORE")).filter(|value| !value.is_empty());
+
+        let admin_token = Some(optional_env("ADMIN_TOKEN")).filter(|value| !value.is_empty());
+
+        let log_format: LogFormat = default_env("LOG_FORMAT", "pretty").try_into()?;
+
+        let server_allow_list = parse_list(&optional_env("SERVER_ALLOW_LIST"));
+        let server_deny_list = parse_list(&optional_env("SERVER_DENY_LIST"));
+
+        let allow_private_addresses = parse_bool(
+            "ALLOW_PRIVATE_ADDRESSES",
+            &default_env("ALLOW_PRIVATE_ADDRESSES", "false"),
+        )?;
+
         Ok(Self {
             version: version()?,
             http_port,
             external_base,
             certificate_bundles,
             user_agent,
+            default_servers,
+            append_default_servers,
+            host_meta_overrides,
+            host_meta_overrides_mode,
+            plc_directory,
+            webfinger,
+            host_meta_min_ttl,
+            host_meta_max_ttl,
+            resolve_concurrency,
+            resolve_deadline,
+            cache_store,
+            admin_token,
+            log_format,
+            server_allow_list,
+            server_deny_list,
+            allow_private_addresses,
         })
     }
 }
@@ -50,6 +177,31 @@ fn default_env(name: &str, default_value: &str) -> String {
     std::env::var(name).unwrap_or(default_value.to_string())
 }
 
+fn parse_bool(name: &str, value: &str) -> Result<bool> {
+    match value.to_lowercase().as_str() {
+        "true" | "1" | "yes" => Ok(true),
+        "false" | "0" | "no" => Ok(false),
+        _ => Err(anyhow!("{} must be true or false", name)),
+    }
+}
+
+fn parse_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+fn parse_seconds(name: &str, value: &str) -> Result<Duration> {
+    value
+        .parse::<u64>()
+        .map(Duration::from_secs)
+        .map_err(|err| {
+            anyhow::Error::new(err).context(anyhow!("{} must be a number of seconds", name))
+        })
+}
+
 pub fn version() -> Result<String> {
     option_env!("GIT_HASH")
         .or(option_env!("CARGO_PKG_VERSION"))
@@ -99,3 +251,82 @@ impl AsRef<Vec<String>> for CertificateBundles {
         &self.0
     }
 }
+
+impl TryFrom<String> for DefaultServers {
+    type Error = anyhow::Error;
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let servers = parse_list(&value);
+        if let Some(server) = servers.iter().find(|server| !is_valid_server(server)) {
+            return Err(anyhow!(
+                "DEFAULT_SERVERS contains an invalid server: {:?}",
+                server
+            ));
+        }
+        Ok(Self(servers))
+    }
+}
+
+impl AsRef<Vec<String>> for DefaultServers {
+    fn as_ref(&self) -> &Vec<String> {
+        &self.0
+    }
+}
+
+impl From<String> for OverrideSource {
+    fn from(value: String) -> Self {
+        match value.as_str() {
+            "" => Self::Bundled,
+            "none" => Self::Disabled,
+            _ => Self::File(value),
+        }
+    }
+}
+
+impl TryFrom<String> for OverrideMode {
+    type Error = anyhow::Error;
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        match value.to_lowercase().as_str() {
+            "override" => Ok(Self::Override),
+            "fallback" => Ok(Self::Fallback),
+            _ => Err(anyhow!(
+                "HOST_META_OVERRIDES_MODE must be one of override or fallback"
+            )),
+        }
+    }
+}
+
+impl TryFrom<String> for LogFormat {
+    type Error = anyhow::Error;
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        match value.to_lowercase().as_str() {
+            "pretty" => Ok(Self::Pretty),
+            "compact" => Ok(Self::Compact),
+            "json" => Ok(Self::Json),
+            _ => Err(anyhow!("LOG_FORMAT must be one of pretty, compact or json")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DefaultServers;
+
+    #[test]
+    fn test_default_servers() {
+        let servers = DefaultServers::try_from("bsky.app, whtwnd.com".to_string()).unwrap();
+        assert_eq!(servers.as_ref(), &vec!["bsky.app", "whtwnd.com"]);
+
+        for value in [
+            "bsky.app,localhost",
+            "127.0.0.1",
+            "bsky.app:8080",
+            "https://bsky.app",
+        ] {
+            assert!(
+                DefaultServers::try_from(value.to_string()).is_err(),
+                "{}",
+                value
+            );
+        }
+    }
+}
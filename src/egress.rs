@@ -0,0 +1,263 @@
+//! Controls over the hosts Hopper makes requests to. Servers are named by
+//! users, so requests are limited to public addresses, redirects are checked
+//! like the servers themselves, and operators can allow or deny servers.
+
+use hickory_resolver::TokioResolver;
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use thiserror::Error;
+
+use crate::model::is_valid_server;
+
+/// The most redirects followed for a single request.
+const MAX_REDIRECTS: usize = 5;
+
+#[derive(Debug, Error)]
+pub(crate) enum EgressError {
+    #[error("{0} has no public addresses")]
+    NoPublicAddress(String),
+
+    #[error("redirect to {0} is not allowed")]
+    ForbiddenRedirect(String),
+
+    #[error("too many redirects")]
+    TooManyRedirects,
+}
+
+/// Which servers may be queried, from the operator's allow and deny lists.
+/// Entries are hostnames, or `*.` followed by a domain to match every
+/// hostname under it. An empty allow list allows every server that is not
+/// denied.
+#[derive(Clone, Debug, Default)]
+pub struct ServerPolicy {
+    allow: Vec<String>,
+    deny: Vec<String>,
+}
+
+impl ServerPolicy {
+    pub fn new(allow: &[String], deny: &[String]) -> Self {
+        let normalize = |patterns: &[String]| {
+            patterns
+                .iter()
+                .map(|pattern| pattern.to_lowercase())
+                .collect::<Vec<_>>()
+        };
+        Self {
+            allow: normalize(allow),
+            deny: normalize(deny),
+        }
+    }
+
+    /// Returns true if the server may be queried.
+    pub fn permits(&self, hostname: &str) -> bool {
+        (self.allow.is_empty() || matches_any(&self.allow, hostname)) && !self.denies(hostname)
+    }
+
+    /// Returns true if the server is on the deny list.
+    pub fn denies(&self, hostname: &str) -> bool {
+        matches_any(&self.deny, hostname)
+    }
+}
+
+fn matches_any(patterns: &[String], hostname: &str) -> bool {
+    let hostname = hostname.to_lowercase();
+    patterns
+        .iter()
+        .any(|pattern| match pattern.strip_prefix("*.") {
+            Some(domain) => hostname
+                .strip_suffix(domain)
+                .is_some_and(|prefix| prefix.ends_with('.')),
+            None => *pattern == hostname,
+        })
+}
+
+/// Returns the redirect policy for the HTTP client. Every hop must be to an
+/// HTTPS URL on a valid server that the policy permits, so that a server
+/// cannot redirect requests somewhere it could not be named directly.
+pub fn redirect_policy(policy: ServerPolicy) -> reqwest::redirect::Policy {
+    reqwest::redirect::Policy::custom(move |attempt| {
+        if attempt.previous().len() >= MAX_REDIRECTS {
+            return attempt.error(EgressError::TooManyRedirects);
+        }
+        if !permits_redirect(&policy, attempt.url()) {
+            let url = attempt.url().to_string();
+            return attempt.error(EgressError::ForbiddenRedirect(url));
+        }
+        attempt.follow()
+    })
+}
+
+/// Returns true if a redirect to the URL may be followed.
+fn permits_redirect(policy: &ServerPolicy, url: &reqwest::Url) -> bool {
+    let host = url.host_str().unwrap_or_default();
+    url.scheme() == "https" && url.port().is_none() && is_valid_server(host) && policy.permits(host)
+}
+
+/// A DNS resolver for the HTTP client that only returns public addresses, so
+/// that hostnames resolving to private networks cannot be reached.
+pub struct PublicResolver {
+    resolver: TokioResolver,
+}
+
+impl PublicResolver {
+    pub fn new(resolver: TokioResolver) -> Self {
+        Self { resolver }
+    }
+}
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let resolver = self.resolver.clone();
+        Box::pin(async move {
+            let lookup = resolver.lookup_ip(name.as_str()).await?;
+            let addrs = lookup
+                .iter()
+                .filter(|ip| is_public_ip(*ip))
+                .map(|ip| SocketAddr::new(ip, 0))
+                .collect::<Vec<_>>();
+            if addrs.is_empty() {
+                return Err(EgressError::NoPublicAddress(name.as_str().to_string()).into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// Returns true if the address is publicly routable.
+pub(crate) fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_ipv4(ip),
+        IpAddr::V6(ip) => match embedded_ipv4(ip) {
+            Some(ip) => is_public_ipv4(ip),
+            None => is_public_ipv6(ip),
+        },
+    }
+}
+
+/// Returns the IPv4 address behind an IPv4-mapped address or a NAT64 address
+/// in the well-known prefix, 64:ff9b::/96.
+fn embedded_ipv4(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    if let Some(ip) = ip.to_ipv4_mapped() {
+        return Some(ip);
+    }
+    let [.., a, b, c, d] = ip.octets();
+    ip.segments()
+        .starts_with(&[0x0064, 0xff9b, 0, 0, 0, 0])
+        .then(|| Ipv4Addr::new(a, b, c, d))
+}
+
+fn is_public_ipv4(ip: Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        // 0.0.0.0/8, "this network"
+        || a == 0
+        // 100.64.0.0/10, shared address space
+        || (a == 100 && (b & 0b1100_0000) == 64)
+        // 192.0.0.0/24, protocol assignments
+        || (a == 192 && b == 0 && c == 0)
+        // 198.18.0.0/15, benchmarking
+        || (a == 198 && (b & 0b1111_1110) == 18)
+        // 240.0.0.0/4, reserved
+        || a >= 240)
+}
+
+fn is_public_ipv6(ip: Ipv6Addr) -> bool {
+    let [a, b, ..] = ip.segments();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        // fc00::/7, unique local
+        || (a & 0xfe00) == 0xfc00
+        // fe80::/10, link-local
+        || (a & 0xffc0) == 0xfe80
+        // 2001:db8::/32, documentation
+        || (a == 0x2001 && b == 0x0db8)
+        // 64:ff9b:1::/48, local-use NAT64 translation of IPv4
+        || (a == 0x0064 && b == 0xff9b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_public_ip, permits_redirect, ServerPolicy};
+
+    #[test]
+    fn test_is_public_ip() {
+        let tests = [
+            ("93.184.215.14", true),
+            ("2606:2800:21f:cb07:6820:80da:af6b:8b2c", true),
+            ("127.0.0.1", false),
+            ("10.1.2.3", false),
+            ("172.16.0.1", false),
+            ("192.168.1.1", false),
+            ("169.254.169.254", false),
+            ("100.64.0.1", false),
+            ("0.0.0.0", false),
+            ("255.255.255.255", false),
+            ("::1", false),
+            ("::", false),
+            ("fd00::1", false),
+            ("fe80::1", false),
+            ("::ffff:127.0.0.1", false),
+            ("::ffff:93.184.215.14", true),
+            ("64:ff9b::7f00:1", false),
+            ("64:ff9b::5db8:d70e", true),
+            ("64:ff9b:1::a00:1", false),
+        ];
+        for (ip, expected) in tests {
+            assert_eq!(is_public_ip(ip.parse().unwrap()), expected, "{}", ip);
+        }
+    }
+
+    #[test]
+    fn test_server_policy() {
+        let policy = ServerPolicy::default();
+        assert!(policy.permits("bsky.app"));
+
+        let policy = ServerPolicy::new(&[], &["*.internal.example.com".to_string()]);
+        assert!(policy.permits("example.com"));
+        assert!(policy.permits("internal.example.com"));
+        assert!(!policy.permits("host.internal.example.com"));
+        assert!(!policy.permits("HOST.Internal.example.com"));
+
+        let policy = ServerPolicy::new(
+            &["bsky.app".to_string(), "*.example.com".to_string()],
+            &["blocked.example.com".to_string()],
+        );
+        assert!(policy.permits("bsky.app"));
+        assert!(policy.permits("a.example.com"));
+        assert!(!policy.permits("example.com"));
+        assert!(!policy.permits("notexample.com"));
+        assert!(!policy.permits("blocked.example.com"));
+        assert!(!policy.permits("frontpage.fyi"));
+    }
+
+    #[test]
+    fn test_permits_redirect() {
+        let open = ServerPolicy::default();
+        let restricted = ServerPolicy::new(
+            &["bsky.app".to_string(), "*.example.com".to_string()],
+            &["blocked.example.com".to_string()],
+        );
+        let tests = [
+            (&open, "https://example.com/host-meta.json", true),
+            (&open, "http://example.com/host-meta.json", false),
+            (&open, "https://example.com:8443/host-meta.json", false),
+            (&open, "https://127.0.0.1/host-meta.json", false),
+            (&open, "https://localhost/host-meta.json", false),
+            (&restricted, "https://bsky.app/host-meta.json", true),
+            (&restricted, "https://cdn.example.com/host-meta.json", true),
+            (&restricted, "https://frontpage.fyi/host-meta.json", false),
+            (&restricted, "https://blocked.example.com/x.json", false),
+        ];
+        for (policy, url, expected) in tests {
+            let url = reqwest::Url::parse(url).unwrap();
+            assert_eq!(permits_redirect(policy, &url), expected, "{}", url);
+        }
+    }
+}
@@ -2,6 +2,7 @@ use axum::{
     http::StatusCode,
     response::{IntoResponse, Response},
 };
+use thiserror::Error;
 
 #[derive(Debug)]
 pub struct HopperError(pub anyhow::Error);
@@ -23,3 +24,51 @@ impl IntoResponse for HopperError {
         }
     }
 }
+
+/// Errors produced while resolving an AT-URI to a destination.
+///
+/// Each message is prefixed with a stable error code that API clients can
+/// match on. Cached failures are stored as strings, so use `split_error_code`
+/// to recover the code from a message.
+#[derive(Clone, Debug, Error)]
+pub(crate) enum AtUriError {
+    #[error("error-web-missing-aturi Missing AT-URI")]
+    Missing,
+
+    #[error("error-web-invalid-aturi Invalid AT-URI")]
+    Invalid,
+
+    #[error("error-web-unsupported-aturi Unsupported AT-URI")]
+    Unsupported,
+
+    #[error("error-web-timeout-aturi Timed out resolving AT-URI")]
+    Timeout,
+
+    #[error("error-web-invalid-server Invalid server")]
+    InvalidServer,
+}
+
+/// Reasons a fetched host-meta or WebFinger document is rejected before or
+/// after it is parsed.
+#[derive(Debug, Error)]
+pub(crate) enum DocumentError {
+    #[error("document is larger than {0} bytes")]
+    TooLarge(usize),
+
+    #[error("unsupported content type {0:?}")]
+    UnsupportedContentType(String),
+
+    #[error("document has more than {0} links")]
+    TooManyLinks(usize),
+
+    #[error("link template is longer than {0} bytes")]
+    TemplateTooLong(usize),
+}
+
+/// Splits an error message into its error code and the remaining message.
+pub(crate) fn split_error_code(message: &str) -> (&str, &str) {
+    match message.split_once(' ') {
+        Some((code, rest)) if code.starts_with("error-") => (code, rest),
+        _ => ("error-web-unknown", message),
+    }
+}
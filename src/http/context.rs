@@ -1,19 +1,20 @@
 use axum::extract::FromRef;
 use axum_template::engine::Engine;
+use metrics_exporter_prometheus::PrometheusHandle;
 use minijinja::Environment;
-use moka::future::Cache;
 use std::{ops::Deref, sync::Arc};
 
-use crate::cache::{ResolveAtUriResult, ResolveWebHostMetaResult};
+use crate::cache::Resolver;
 
 pub type AppEngine = Engine<Environment<'static>>;
 
 pub struct InnerWebContext {
     pub(crate) external_base: String,
     pub(crate) engine: AppEngine,
-    pub(crate) http_client: reqwest::Client,
-    pub(crate) resolve_webfinger_cache: Cache<String, ResolveWebHostMetaResult>,
-    pub(crate) resolve_aturi_cache: Cache<String, ResolveAtUriResult>,
+    pub(crate) default_servers: Vec<String>,
+    pub(crate) resolver: Resolver,
+    pub(crate) admin_token: Option<String>,
+    pub(crate) metrics: PrometheusHandle,
 }
 
 #[derive(Clone, FromRef)]
@@ -31,16 +32,18 @@ impl WebContext {
     pub fn new(
         external_base: &str,
         engine: AppEngine,
-        http_client: &reqwest::Client,
-        resolve_webfinger_cache: Cache<String, ResolveWebHostMetaResult>,
-        resolve_aturi_cache: Cache<String, ResolveAtUriResult>,
+        default_servers: &[String],
+        resolver: Resolver,
+        admin_token: Option<String>,
+        metrics: PrometheusHandle,
     ) -> Self {
         Self(Arc::new(InnerWebContext {
             external_base: external_base.to_string(),
             engine,
-            http_client: http_client.clone(),
-            resolve_webfinger_cache,
-            resolve_aturi_cache,
+            default_servers: default_servers.to_vec(),
+            resolver,
+            admin_token,
+            metrics,
         }))
     }
 }
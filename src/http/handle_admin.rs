@@ -0,0 +1,248 @@
+use anyhow::Result;
+use axum::{
+    extract::{Path, Request, State},
+    http::{header::AUTHORIZATION, header::WWW_AUTHENTICATE, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{
+    cache::{CachedAtUri, Resolution, ResolveAtUriResult, ResolveWebHostMetaResult},
+    errors::HopperError,
+    http::{context::WebContext, handle_api_resolve::error_response},
+    webhostmeta::{Format, WebHostMeta},
+};
+
+const ERROR_NOT_CACHED: &str = "error-admin-not-cached Not cached";
+
+const OUTCOME_FOUND: &str = "found";
+
+const OUTCOME_NOT_FOUND: &str = "not-found";
+
+/// The scope of a purge. Only the memory of the instance that handled the
+/// request is cleared, along with the cache store. Other instances sharing
+/// the store keep their in-memory entries until they expire.
+const PURGE_SCOPE: &str = "instance";
+
+/// A host-meta cache entry. Times are in seconds since the epoch.
+#[derive(Serialize)]
+struct WebHostMetaEntry {
+    key: String,
+    outcome: &'static str,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    url: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    format: Option<Format>,
+
+    /// When the document is next revalidated.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fresh_until: Option<u64>,
+
+    /// When the document is dropped if it cannot be revalidated.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stale_until: Option<u64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    webhostmeta: Option<WebHostMeta>,
+}
+
+impl WebHostMetaEntry {
+    fn new(key: String, value: ResolveWebHostMetaResult) -> Self {
+        match value {
+            ResolveWebHostMetaResult::Found(cached) => Self {
+                key,
+                outcome: OUTCOME_FOUND,
+                url: Some(cached.fetched.url),
+                format: Some(cached.fetched.format),
+                fresh_until: Some(unix_seconds(cached.fresh_until)),
+                stale_until: Some(unix_seconds(cached.stale_until)),
+                error: None,
+                webhostmeta: Some(cached.fetched.webhostmeta),
+            },
+            ResolveWebHostMetaResult::NotFound(err) => Self {
+                key,
+                outcome: OUTCOME_NOT_FOUND,
+                url: None,
+                format: None,
+                fresh_until: None,
+                stale_until: None,
+                error: Some(err),
+                webhostmeta: None,
+            },
+        }
+    }
+}
+
+/// An AT-URI cache entry, with the AT-URI as given and the servers that were
+/// considered for it.
+#[derive(Serialize)]
+struct AtUriEntry {
+    key: String,
+    aturi: String,
+    servers: Vec<String>,
+    outcome: &'static str,
+
+    #[serde(flatten)]
+    resolution: Option<Resolution>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl AtUriEntry {
+    fn new(key: String, value: CachedAtUri) -> Self {
+        let CachedAtUri {
+            aturi,
+            servers,
+            result,
+        } = value;
+        match result {
+            ResolveAtUriResult::Found(resolution) => Self {
+                key,
+                aturi,
+                servers,
+                outcome: OUTCOME_FOUND,
+                resolution: Some(resolution),
+                error: None,
+            },
+            ResolveAtUriResult::NotFound(err) => Self {
+                key,
+                aturi,
+                servers,
+                outcome: OUTCOME_NOT_FOUND,
+                resolution: None,
+                error: Some(err),
+            },
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct PurgeResponse {
+    purged: usize,
+    scope: &'static str,
+}
+
+fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Compares two tokens in time that depends only on their lengths.
+fn tokens_match(expected: &str, given: &str) -> bool {
+    expected.len() == given.len()
+        && expected
+            .bytes()
+            .zip(given.bytes())
+            .fold(0, |difference, (a, b)| difference | (a ^ b))
+            == 0
+}
+
+/// Rejects requests that do not carry the admin token as a bearer token.
+pub(crate) async fn require_admin_token(
+    State(web_context): State<WebContext>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let given = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    match (web_context.admin_token.as_deref(), given) {
+        (Some(expected), Some(given)) if tokens_match(expected, given) => next.run(request).await,
+        _ => (StatusCode::UNAUTHORIZED, [(WWW_AUTHENTICATE, "Bearer")]).into_response(),
+    }
+}
+
+/// Lists the host-meta cache entries held in memory, without their
+/// documents.
+pub(crate) async fn handle_admin_list_hostnames(
+    State(web_context): State<WebContext>,
+) -> Result<impl IntoResponse, HopperError> {
+    let entries = web_context
+        .resolver
+        .webhostmeta_entries()
+        .into_iter()
+        .map(|(key, value)| WebHostMetaEntry {
+            webhostmeta: None,
+            ..WebHostMetaEntry::new(key, value)
+        })
+        .collect::<Vec<_>>();
+    Ok(Json(entries))
+}
+
+/// Shows the cached host-meta entry for a hostname, including its document.
+pub(crate) async fn handle_admin_view_hostname(
+    State(web_context): State<WebContext>,
+    Path(hostname): Path<String>,
+) -> Result<impl IntoResponse, HopperError> {
+    match web_context.resolver.webhostmeta_entry(&hostname).await {
+        Some(value) => Ok(Json(WebHostMetaEntry::new(hostname, value)).into_response()),
+        None => Ok(error_response(StatusCode::NOT_FOUND, ERROR_NOT_CACHED)),
+    }
+}
+
+/// Purges everything cached for a hostname, so that its host-meta document
+/// is fetched again and AT-URIs that considered it are resolved again on the
+/// next resolution by this instance.
+pub(crate) async fn handle_admin_purge_hostname(
+    State(web_context): State<WebContext>,
+    Path(hostname): Path<String>,
+) -> Result<impl IntoResponse, HopperError> {
+    let purged = web_context.resolver.purge_hostname(&hostname).await?;
+    tracing::info!(hostname, purged, "purged hostname cache entries");
+    Ok(Json(PurgeResponse {
+        purged,
+        scope: PURGE_SCOPE,
+    }))
+}
+
+/// Lists the AT-URI cache entries held in memory.
+pub(crate) async fn handle_admin_list_aturis(
+    State(web_context): State<WebContext>,
+) -> Result<impl IntoResponse, HopperError> {
+    let entries = web_context
+        .resolver
+        .aturi_entries()
+        .into_iter()
+        .map(|(key, value)| AtUriEntry::new(key, value))
+        .collect::<Vec<_>>();
+    Ok(Json(entries))
+}
+
+/// Purges an AT-URI cache entry by its cache key.
+pub(crate) async fn handle_admin_purge_aturi(
+    State(web_context): State<WebContext>,
+    Path(cache_key): Path<String>,
+) -> Result<impl IntoResponse, HopperError> {
+    let purged = web_context.resolver.purge_aturi(&cache_key).await?;
+    tracing::info!(cache_key, purged, "purged AT-URI cache entry");
+    Ok(Json(PurgeResponse {
+        purged: usize::from(purged),
+        scope: PURGE_SCOPE,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::tokens_match;
+
+    #[test]
+    fn test_tokens_match() {
+        assert!(tokens_match("secret", "secret"));
+        assert!(!tokens_match("secret", "secreT"));
+        assert!(!tokens_match("secret", "secret2"));
+        assert!(!tokens_match("secret", ""));
+    }
+}
@@ -0,0 +1,288 @@
+use anyhow::Result;
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cache::Resolution,
+    errors::{split_error_code, AtUriError, HopperError},
+    http::{
+        context::WebContext,
+        handle_index::{
+            parse_servers, MODE_CHOOSE, OUTCOME_CANDIDATES, OUTCOME_FOUND, OUTCOME_INVALID,
+            OUTCOME_NOT_FOUND,
+        },
+    },
+    model::validate_aturi,
+};
+
+#[derive(Deserialize)]
+pub(crate) struct ResolveRequest {
+    aturi: Option<String>,
+    server: Option<String>,
+    mode: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ResolveResponse {
+    aturi: String,
+
+    /// Whether the record key is a TID, when the AT-URI has one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tid: Option<bool>,
+
+    #[serde(flatten)]
+    resolution: Resolution,
+}
+
+#[derive(Serialize)]
+struct CandidatesResponse {
+    aturi: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tid: Option<bool>,
+    candidates: Vec<Resolution>,
+}
+
+#[derive(Serialize)]
+struct ErrorResponse {
+    error: ErrorDetail,
+}
+
+#[derive(Serialize)]
+struct ErrorDetail {
+    code: String,
+    message: String,
+}
+
+pub(crate) fn error_response(status: StatusCode, message: &str) -> Response {
+    let (code, message) = split_error_code(message);
+    (
+        status,
+        Json(ErrorResponse {
+            error: ErrorDetail {
+                code: code.to_string(),
+                message: message.to_string(),
+            },
+        }),
+    )
+        .into_response()
+}
+
+/// Resolves an AT-URI and returns the destination as JSON instead of
+/// redirecting to it.
+pub(crate) async fn handle_api_resolve(
+    State(web_context): State<WebContext>,
+    Query(request): Query<ResolveRequest>,
+) -> Result<impl IntoResponse, HopperError> {
+    let span = tracing::Span::current();
+
+    let Some(aturi_str) = request.aturi else {
+        span.record("outcome", OUTCOME_INVALID);
+        return Ok(error_response(
+            StatusCode::BAD_REQUEST,
+            &AtUriError::Missing.to_string(),
+        ));
+    };
+
+    span.record("aturi", &aturi_str);
+
+    let Some(aturi) = validate_aturi(&aturi_str) else {
+        span.record("outcome", OUTCOME_INVALID);
+        return Ok(error_response(
+            StatusCode::BAD_REQUEST,
+            &AtUriError::Invalid.to_string(),
+        ));
+    };
+
+    let tid = aturi.rkey.as_ref().map(|_| aturi.has_tid_rkey());
+
+    let servers = match parse_servers(
+        &request.server.unwrap_or_default(),
+        &web_context.default_servers,
+    ) {
+        Ok(servers) => servers,
+        Err(err) => {
+            let error_message = err.to_string();
+            span.record("outcome", split_error_code(&error_message).0);
+            return Ok(error_response(StatusCode::BAD_REQUEST, &error_message));
+        }
+    };
+
+    if request.mode.as_deref() == Some(MODE_CHOOSE) {
+        let candidates = web_context
+            .resolver
+            .aturi_candidates(&servers, &aturi)
+            .await;
+
+        if candidates.is_empty() {
+            span.record("outcome", OUTCOME_NOT_FOUND);
+            return Ok(error_response(
+                StatusCode::NOT_FOUND,
+                &AtUriError::Unsupported.to_string(),
+            ));
+        }
+
+        span.record("outcome", OUTCOME_CANDIDATES);
+        return Ok(Json(CandidatesResponse {
+            aturi: aturi_str,
+            tid,
+            candidates,
+        })
+        .into_response());
+    }
+
+    let resolution = web_context
+        .resolver
+        .aturi_cached(&servers, &aturi_str, &aturi)
+        .await;
+
+    match resolution {
+        Ok(resolution) => {
+            span.record("server", &resolution.server);
+            span.record("outcome", OUTCOME_FOUND);
+            Ok(Json(ResolveResponse {
+                aturi: aturi_str,
+                tid,
+                resolution,
+            })
+            .into_response())
+        }
+        Err(err) => {
+            tracing::debug!(error = ?err, "error encountered");
+            span.record("outcome", split_error_code(&err.to_string()).0);
+            let status = match err.downcast_ref::<AtUriError>() {
+                Some(AtUriError::Timeout) => StatusCode::GATEWAY_TIMEOUT,
+                _ => StatusCode::NOT_FOUND,
+            };
+            Ok(error_response(status, &err.to_string()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        extract::{Query, State},
+        http::StatusCode,
+        response::IntoResponse,
+    };
+    use metrics_exporter_prometheus::PrometheusBuilder;
+
+    use super::{handle_api_resolve, ResolveRequest};
+    use crate::{
+        cache::{tests::test_resolver, ResolverOptions},
+        egress::ServerPolicy,
+        http::{
+            context::{AppEngine, WebContext},
+            templates,
+        },
+    };
+
+    /// Returns a context whose resolver may only query `allowed.example`, so
+    /// that other servers have no links without any requests being made.
+    fn test_context() -> WebContext {
+        let resolver = test_resolver(ResolverOptions {
+            server_policy: ServerPolicy::new(&["allowed.example".to_string()], &[]),
+            ..ResolverOptions::default()
+        });
+        WebContext::new(
+            "https://hopper.example",
+            AppEngine::from(templates::build_env(
+                "https://hopper.example".to_string(),
+                "test".to_string(),
+            )),
+            &[],
+            resolver,
+            None,
+            PrometheusBuilder::new().build_recorder().handle(),
+        )
+    }
+
+    /// Resolves a request and returns the response status and error code.
+    async fn resolve(
+        aturi: Option<&str>,
+        server: Option<&str>,
+        mode: Option<&str>,
+    ) -> (StatusCode, String) {
+        let request = ResolveRequest {
+            aturi: aturi.map(str::to_string),
+            server: server.map(str::to_string),
+            mode: mode.map(str::to_string),
+        };
+        let response = handle_api_resolve(State(test_context()), Query(request))
+            .await
+            .unwrap()
+            .into_response();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let code = body["error"]["code"].as_str().unwrap_or_default();
+        (status, code.to_string())
+    }
+
+    #[tokio::test]
+    async fn test_api_resolve_errors() {
+        let aturi = "at://alice.example.com/app.bsky.feed.post/abc123";
+        let tests = [
+            (
+                None,
+                Some("a.example"),
+                None,
+                StatusCode::BAD_REQUEST,
+                "error-web-missing-aturi",
+            ),
+            (
+                Some("at://alice/app.bsky.feed.post"),
+                Some("a.example"),
+                None,
+                StatusCode::BAD_REQUEST,
+                "error-web-invalid-aturi",
+            ),
+            (
+                Some("at://did:plc:..%2F..%2Fadmin%2Fxxxxxxxxxxxx/app.bsky.feed.post"),
+                Some("a.example"),
+                None,
+                StatusCode::BAD_REQUEST,
+                "error-web-invalid-aturi",
+            ),
+            (
+                Some(aturi),
+                Some("127.0.0.1"),
+                None,
+                StatusCode::BAD_REQUEST,
+                "error-web-invalid-server",
+            ),
+            (
+                Some(aturi),
+                Some("a.example"),
+                None,
+                StatusCode::NOT_FOUND,
+                "error-web-unsupported-aturi",
+            ),
+            (
+                Some(aturi),
+                Some("a.example"),
+                Some("choose"),
+                StatusCode::NOT_FOUND,
+                "error-web-unsupported-aturi",
+            ),
+        ];
+        for (aturi, server, mode, status, code) in tests {
+            assert_eq!(
+                resolve(aturi, server, mode).await,
+                (status, code.to_string()),
+                "{:?} {:?} {:?}",
+                aturi,
+                server,
+                mode
+            );
+        }
+    }
+}
@@ -9,18 +9,29 @@ use ordermap::OrderSet;
 use serde::Deserialize;
 
 use crate::{
-    cache::aturi_cached,
-    errors::HopperError,
+    errors::{split_error_code, AtUriError, HopperError},
     http::context::WebContext,
-    model::validate_aturi,
+    model::{is_valid_server, validate_aturi},
 };
 
 pub(crate) const ERROR_INVALID_AT_URI: &str = "Invalid AT-URI";
 
+/// The `mode` value that lists every matching destination instead of
+/// redirecting to the first one.
+pub(crate) const MODE_CHOOSE: &str = "choose";
+
+/// Outcomes recorded on request spans, alongside the error codes of failed
+/// resolutions.
+pub(crate) const OUTCOME_FOUND: &str = "found";
+pub(crate) const OUTCOME_CANDIDATES: &str = "candidates";
+pub(crate) const OUTCOME_NOT_FOUND: &str = "not-found";
+pub(crate) const OUTCOME_INVALID: &str = "invalid";
+
 #[derive(Deserialize)]
 pub(crate) struct Destination {
     aturi: Option<String>,
     server: Option<String>,
+    mode: Option<String>,
 }
 
 pub(crate) async fn handle_index(
@@ -32,8 +43,12 @@ pub(crate) async fn handle_index(
     };
 
     if let Some(aturi_str) = destination.aturi {
+        let span = tracing::Span::current();
+        span.record("aturi", &aturi_str);
+
         let aturi = validate_aturi(&aturi_str);
         if aturi.is_none() {
+            span.record("outcome", OUTCOME_INVALID);
             tracing::debug!(error = ERROR_INVALID_AT_URI, "error encountered");
 
             return Ok(RenderHtml(
@@ -50,21 +65,69 @@ pub(crate) async fn handle_index(
 
         let aturi = aturi.unwrap();
 
-        let servers = parse_servers(&destination.server.unwrap_or_default());
+        let servers = match parse_servers(
+            &destination.server.unwrap_or_default(),
+            &web_context.default_servers,
+        ) {
+            Ok(servers) => servers,
+            Err(err) => {
+                let error_message = err.to_string();
+                span.record("outcome", split_error_code(&error_message).0);
+
+                return Ok(RenderHtml(
+                    "index.html",
+                    web_context.engine.clone(),
+                    template_context! { ..default_context, ..template_context! {
+                        handle_error => true,
+                        aturi_value => aturi_str,
+                        aturi_error => error_message,
+                    }},
+                )
+                .into_response());
+            }
+        };
+
+        if destination.mode.as_deref() == Some(MODE_CHOOSE) {
+            let candidates = web_context
+                .resolver
+                .aturi_candidates(&servers, &aturi)
+                .await;
+
+            if candidates.is_empty() {
+                span.record("outcome", OUTCOME_NOT_FOUND);
+                return Ok(RenderHtml(
+                    "index.html",
+                    web_context.engine.clone(),
+                    template_context! { ..default_context, ..template_context! {
+                        handle_error => true,
+                        aturi_value => aturi_str,
+                        aturi_error => AtUriError::Unsupported.to_string(),
+                    }},
+                )
+                .into_response());
+            }
+
+            span.record("outcome", OUTCOME_CANDIDATES);
+            return Ok(RenderHtml(
+                "choose.html",
+                web_context.engine.clone(),
+                template_context! { ..default_context, ..template_context! {
+                    aturi_value => aturi_str,
+                    candidates => candidates,
+                }},
+            )
+            .into_response());
+        }
 
-        let destination = aturi_cached(
-            &web_context.http_client,
-            &web_context.resolve_webfinger_cache,
-            &web_context.resolve_aturi_cache,
-            &servers,
-            &aturi_str,
-            &aturi,
-        )
-        .await;
+        let resolution = web_context
+            .resolver
+            .aturi_cached(&servers, &aturi_str, &aturi)
+            .await;
 
-        if let Err(err) = destination {
+        if let Err(err) = resolution {
             tracing::debug!(error = ?err, "error encountered");
             let error_message = err.to_string();
+            span.record("outcome", split_error_code(&error_message).0);
 
             return Ok(RenderHtml(
                 "index.html",
@@ -78,9 +141,11 @@ pub(crate) async fn handle_index(
             .into_response());
         }
 
-        let destination = destination.unwrap();
+        let resolution = resolution.unwrap();
+        span.record("server", &resolution.server);
+        span.record("outcome", OUTCOME_FOUND);
 
-        return Ok(Redirect::to(&destination).into_response());
+        return Ok(Redirect::to(&resolution.destination).into_response());
     }
 
     Ok(RenderHtml(
@@ -91,19 +156,25 @@ pub(crate) async fn handle_index(
     .into_response())
 }
 
-fn parse_servers(value: &str) -> Vec<String> {
+/// Parses the comma separated `server` query string parameter and appends the
+/// default servers, skipping duplicates while preserving order. Every given
+/// server must be a hostname, so that requests cannot be made to IP addresses
+/// or other ports.
+pub(crate) fn parse_servers(
+    value: &str,
+    default_servers: &[String],
+) -> Result<Vec<String>, AtUriError> {
     let mut values = value
         .split(',')
         .map(|s| s.trim().to_string())
         .filter(|s| !s.is_empty())
         .collect::<OrderSet<String>>();
 
-    values.extend(vec![
-        "smokesignal.events".into(),
-        "frontpage.fyi".into(),
-        "whtwnd.com".into(),
-        "bsky.app".into(),
-    ]);
+    if !values.iter().all(|server| is_valid_server(server)) {
+        return Err(AtUriError::InvalidServer);
+    }
+
+    values.extend(default_servers.iter().cloned());
 
-    Vec::from_iter(values)
+    Ok(Vec::from_iter(values))
 }
@@ -0,0 +1,16 @@
+use anyhow::Result;
+use axum::{extract::State, http::header::CONTENT_TYPE, response::IntoResponse};
+
+use crate::{errors::HopperError, http::context::WebContext};
+
+/// Renders the metrics in the Prometheus text format.
+pub(crate) async fn handle_metrics(
+    State(web_context): State<WebContext>,
+) -> Result<impl IntoResponse, HopperError> {
+    web_context.resolver.record_cache_entries().await;
+
+    Ok((
+        [(CONTENT_TYPE, "text/plain; version=0.0.4")],
+        web_context.metrics.render(),
+    ))
+}
@@ -1,5 +1,8 @@
 pub mod context;
+pub(crate) mod handle_admin;
+pub(crate) mod handle_api_resolve;
 pub(crate) mod handle_index;
+pub(crate) mod handle_metrics;
 pub(crate) mod handle_policy;
 pub(crate) mod handle_spec;
 pub mod server;
@@ -1,19 +1,92 @@
 use std::time::Duration;
 
-use axum::{http::HeaderValue, routing::get, Router};
+use axum::{
+    extract::Request,
+    http::HeaderValue,
+    middleware,
+    response::Response,
+    routing::{delete, get},
+    Router,
+};
 use http::{
     header::{ACCEPT, ACCEPT_LANGUAGE},
     Method,
 };
 use tower_http::{cors::CorsLayer, services::ServeDir};
+use tower_http::request_id::{
+    MakeRequestUuid, PropagateRequestIdLayer, RequestId, SetRequestIdLayer,
+};
 use tower_http::timeout::TimeoutLayer;
-use tower_http::trace::TraceLayer;
+use tower_http::trace::{DefaultOnResponse, OnResponse, TraceLayer};
+use tracing::Level;
 
 use crate::http::{
-    context::WebContext, handle_index::handle_index, handle_policy::handle_policy,
+    context::WebContext,
+    handle_admin::{
+        handle_admin_list_aturis, handle_admin_list_hostnames, handle_admin_purge_aturi,
+        handle_admin_purge_hostname, handle_admin_view_hostname, require_admin_token,
+    },
+    handle_api_resolve::handle_api_resolve,
+    handle_index::handle_index,
+    handle_metrics::handle_metrics,
+    handle_policy::handle_policy,
     handle_spec::handle_spec,
 };
 
+/// Opens the span for a request, carrying the request id so that every log
+/// line written while handling the request can be traced back to it. Handlers
+/// record the AT-URI, the matched server and the outcome on it, and the
+/// latency is recorded when the response is sent.
+fn request_span(request: &Request) -> tracing::Span {
+    let request_id = request
+        .extensions()
+        .get::<RequestId>()
+        .and_then(|request_id| request_id.header_value().to_str().ok())
+        .unwrap_or_default();
+    tracing::info_span!(
+        "request",
+        request_id,
+        method = %request.method(),
+        uri = %request.uri(),
+        aturi = tracing::field::Empty,
+        server = tracing::field::Empty,
+        outcome = tracing::field::Empty,
+        latency_ms = tracing::field::Empty,
+    )
+}
+
+/// Records the latency on the request span and logs the response.
+fn on_response(response: &Response, latency: Duration, span: &tracing::Span) {
+    span.record("latency_ms", latency.as_millis() as u64);
+    DefaultOnResponse::new()
+        .level(Level::INFO)
+        .on_response(response, latency, span);
+}
+
+/// Routes for inspecting and purging the resolution caches. They are only
+/// served when an admin token is configured.
+fn admin_router(web_context: &WebContext) -> Router<WebContext> {
+    if web_context.admin_token.is_none() {
+        return Router::new();
+    }
+
+    Router::new()
+        .route("/admin/cache/hostnames", get(handle_admin_list_hostnames))
+        .route(
+            "/admin/cache/hostnames/{hostname}",
+            get(handle_admin_view_hostname).delete(handle_admin_purge_hostname),
+        )
+        .route("/admin/cache/aturis", get(handle_admin_list_aturis))
+        .route(
+            "/admin/cache/aturis/{key}",
+            delete(handle_admin_purge_aturi),
+        )
+        .route_layer(middleware::from_fn_with_state(
+            web_context.clone(),
+            require_admin_token,
+        ))
+}
+
 pub fn build_router(web_context: WebContext) -> Router {
     let serve_dir = ServeDir::new("static");
 
@@ -21,11 +94,18 @@ pub fn build_router(web_context: WebContext) -> Router {
         .route("/", get(handle_index))
         .route("/spec", get(handle_spec))
         .route("/policy", get(handle_policy))
+        .route("/api/resolve", get(handle_api_resolve))
+        .route("/metrics", get(handle_metrics))
+        .merge(admin_router(&web_context))
         .nest_service("/static", serve_dir.clone())
         .fallback_service(serve_dir)
         .layer((
-            TraceLayer::new_for_http(),
+            SetRequestIdLayer::x_request_id(MakeRequestUuid),
+            TraceLayer::new_for_http()
+                .make_span_with(request_span)
+                .on_response(on_response),
             TimeoutLayer::new(Duration::from_secs(10)),
+            PropagateRequestIdLayer::x_request_id(),
         ))
         .layer(
             CorsLayer::new()
@@ -0,0 +1,122 @@
+//! Freshness lifetimes from HTTP response caching headers, following the
+//! parts of RFC 9111 that apply to a shared cache.
+
+use reqwest::header::{HeaderMap, AGE, CACHE_CONTROL, DATE, EXPIRES};
+use std::time::{Duration, SystemTime};
+
+/// Returns how long a response stays fresh according to its headers, or
+/// `None` if the headers do not say. `no-store` and `no-cache` responses
+/// are stale immediately. `s-maxage` takes precedence over `max-age`, which
+/// takes precedence over `Expires`. The `Age` header is subtracted.
+pub(crate) fn max_age(headers: &HeaderMap, now: SystemTime) -> Option<Duration> {
+    let age = header(headers, AGE)
+        .and_then(|value| value.trim().parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or_default();
+
+    let mut max_age = None;
+    let mut shared_max_age = None;
+    for directive in headers
+        .get_all(CACHE_CONTROL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+    {
+        let (name, value) = directive
+            .split_once('=')
+            .map(|(name, value)| (name, Some(value.trim().trim_matches('"'))))
+            .unwrap_or((directive, None));
+        let seconds = value
+            .and_then(|value| value.parse().ok())
+            .map(Duration::from_secs);
+        match name.trim().to_ascii_lowercase().as_str() {
+            "no-store" | "no-cache" => return Some(Duration::ZERO),
+            "s-maxage" => shared_max_age = shared_max_age.or(seconds),
+            "max-age" => max_age = max_age.or(seconds),
+            _ => {}
+        }
+    }
+
+    if let Some(lifetime) = shared_max_age.or(max_age) {
+        return Some(lifetime.saturating_sub(age));
+    }
+
+    // An invalid Expires value, such as "0", means the response is already
+    // expired.
+    let expires = header(headers, EXPIRES)?;
+    let Ok(expires) = httpdate::parse_http_date(expires) else {
+        return Some(Duration::ZERO);
+    };
+    let date = header(headers, DATE)
+        .and_then(|value| httpdate::parse_http_date(value).ok())
+        .unwrap_or(now);
+    Some(
+        expires
+            .duration_since(date)
+            .unwrap_or_default()
+            .saturating_sub(age),
+    )
+}
+
+fn header(headers: &HeaderMap, name: reqwest::header::HeaderName) -> Option<&str> {
+    headers.get(name).and_then(|value| value.to_str().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::header::{HeaderMap, HeaderValue, AGE, CACHE_CONTROL, DATE, EXPIRES};
+    use std::time::{Duration, SystemTime};
+
+    use super::max_age;
+
+    fn headers(values: &[(reqwest::header::HeaderName, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in values {
+            headers.append(name.clone(), HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    #[test]
+    fn test_max_age() {
+        let now = SystemTime::now();
+        let cases = [
+            (headers(&[]), None),
+            (
+                headers(&[(CACHE_CONTROL, "public, max-age=300")]),
+                Some(Duration::from_secs(300)),
+            ),
+            (
+                headers(&[(CACHE_CONTROL, "max-age=\"300\""), (AGE, "100")]),
+                Some(Duration::from_secs(200)),
+            ),
+            (
+                headers(&[(CACHE_CONTROL, "max-age=300, s-maxage=60")]),
+                Some(Duration::from_secs(60)),
+            ),
+            (
+                headers(&[(CACHE_CONTROL, "max-age=300"), (CACHE_CONTROL, "no-cache")]),
+                Some(Duration::ZERO),
+            ),
+            (
+                headers(&[
+                    (DATE, "Sun, 18 Oct 2026 10:00:00 GMT"),
+                    (EXPIRES, "Sun, 18 Oct 2026 11:00:00 GMT"),
+                ]),
+                Some(Duration::from_secs(3600)),
+            ),
+            (
+                headers(&[
+                    (CACHE_CONTROL, "max-age=60"),
+                    (DATE, "Sun, 18 Oct 2026 10:00:00 GMT"),
+                    (EXPIRES, "Sun, 18 Oct 2026 11:00:00 GMT"),
+                ]),
+                Some(Duration::from_secs(60)),
+            ),
+            (headers(&[(EXPIRES, "0")]), Some(Duration::ZERO)),
+        ];
+        for (headers, expected) in cases {
+            assert_eq!(max_age(&headers, now), expected, "{:?}", headers);
+        }
+    }
+}
@@ -0,0 +1,315 @@
+use anyhow::{anyhow, Context, Result};
+use hickory_resolver::TokioResolver;
+use moka::future::Cache;
+use serde::Deserialize;
+use std::time::Duration;
+
+use crate::{model::is_valid_server, webhostmeta::read_limited};
+
+/// The largest atproto-did or DID document response that is read.
+const MAX_IDENTITY_DOCUMENT_SIZE: usize = 16 * 1024;
+
+/// The identity behind an AT-URI authority. Either value may be missing when
+/// it could not be resolved or verified.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct Identity {
+    pub(crate) did: Option<String>,
+    pub(crate) handle: Option<String>,
+}
+
+impl Identity {
+    /// Returns the unresolved identity for an authority, which only knows the
+    /// form the authority was given in.
+    pub(crate) fn from_authority(authority: &str) -> Self {
+        if authority.starts_with("did:") {
+            Self {
+                did: Some(authority.to_string()),
+                handle: None,
+            }
+        } else {
+            Self {
+                did: None,
+                handle: Some(authority.to_lowercase()),
+            }
+        }
+    }
+
+    /// Returns true if the value is this identity's DID or handle. Handles are
+    /// compared case-insensitively.
+    pub(crate) fn is(&self, value: &str) -> bool {
+        self.did.as_deref() == Some(value)
+            || self
+                .handle
+                .as_deref()
+                .is_some_and(|handle| handle.eq_ignore_ascii_case(value))
+    }
+}
+
+#[derive(Deserialize)]
+struct DidDocument {
+    id: String,
+
+    #[serde(rename = "alsoKnownAs", default)]
+    also_known_as: Vec<String>,
+}
+
+impl DidDocument {
+    /// Returns the handles claimed by the document.
+    fn handles(&self) -> impl Iterator<Item = &str> {
+        self.also_known_as
+            .iter()
+            .filter_map(|value| value.strip_prefix("at://"))
+    }
+}
+
+/// Resolves handles to DIDs and DIDs to handles, verifying that both sides
+/// agree before reporting a handle and DID as the same identity.
+///
+/// Handles and did:web hosts are named by users and fetched with the
+/// public-only HTTP client. The PLC directory is configured by the operator,
+/// so it is fetched with its own client that may reach private addresses.
+#[derive(Clone)]
+pub struct IdentityResolver {
+    http_client: reqwest::Client,
+    plc_client: reqwest::Client,
+    dns_resolver: TokioResolver,
+    plc_directory: String,
+    cache: Cache<String, Identity>,
+}
+
+impl IdentityResolver {
+    pub fn new(
+        http_client: &reqwest::Client,
+        plc_client: &reqwest::Client,
+        dns_resolver: TokioResolver,
+        plc_directory: &str,
+    ) -> Self {
+        Self {
+            http_client: http_client.clone(),
+            plc_client: plc_client.clone(),
+            dns_resolver,
+            plc_directory: plc_directory.trim_end_matches('/').to_string(),
+            cache: Cache::builder()
+                .max_capacity(1024 * 20)
+                .time_to_live(Duration::from_secs(60 * 30))
+                .build(),
+        }
+    }
+
+    /// Resolves the identity for an authority. Resolution failures are not
+    /// errors; the returned identity only contains what could be verified.
+    pub(crate) async fn resolve(&self, authority: &str) -> Identity {
+        if let Some(identity) = self.cache.get(authority).await {
+            return identity;
+        }
+
+        let identity = if authority.starts_with("did:") {
+            self.resolve_from_did(authority).await
+        } else {
+            self.resolve_from_handle(authority).await
+        };
+
+        let identity = identity.unwrap_or_else(|err| {
+            tracing::debug!(error = ?err, authority, "identity resolution failed");
+            Identity::from_authority(authority)
+        });
+
+        self.cache
+            .insert(authority.to_string(), identity.clone())
+            .await;
+        identity
+    }
+
+    async fn resolve_from_did(&self, did: &str) -> Result<Identity> {
+        let document = self.resolve_did_document(did).await?;
+
+        let mut identity = Identity::from_authority(did);
+        if let Some(handle) = document.handles().next() {
+            match self.resolve_handle(handle).await {
+                Ok(resolved_did) if resolved_did == did => {
+                    identity.handle = Some(handle.to_lowercase());
+                }
+                Ok(_) => tracing::debug!(did, handle, "handle does not resolve to did"),
+                Err(err) => tracing::debug!(error = ?err, handle, "handle resolution failed"),
+            }
+        }
+        Ok(identity)
+    }
+
+    async fn resolve_from_handle(&self, handle: &str) -> Result<Identity> {
+        let did = self.resolve_handle(handle).await?;
+        let document = self.resolve_did_document(&did).await?;
+
+        let mut identity = Identity::from_authority(handle);
+        if document
+            .handles()
+            .any(|claimed| claimed.eq_ignore_ascii_case(handle))
+        {
+            identity.did = Some(did);
+        } else {
+            tracing::debug!(did, handle, "did document does not claim handle");
+        }
+        Ok(identity)
+    }
+
+    /// Resolves a handle to a DID using the `_atproto` DNS TXT record, falling
+    /// back to `/.well-known/atproto-did`.
+    async fn resolve_handle(&self, handle: &str) -> Result<String> {
+        if !is_valid_server(handle) {
+            return Err(anyhow!("invalid handle"));
+        }
+
+        match self.resolve_handle_dns(handle).await {
+            Ok(did) => Ok(did),
+            Err(err) => {
+                tracing::debug!(error = ?err, handle, "dns handle resolution failed");
+                self.resolve_handle_http(handle).await
+            }
+        }
+    }
+
+    async fn resolve_handle_dns(&self, handle: &str) -> Result<String> {
+        let lookup = self
+            .dns_resolver
+            .txt_lookup(format!("_atproto.{}.", handle))
+            .await
+            .context("txt lookup failed")?;
+
+        let dids = lookup
+            .iter()
+            .map(|txt| {
+                txt.txt_data()
+                    .iter()
+                    .map(|data| String::from_utf8_lossy(data))
+                    .collect::<String>()
+            })
+            .filter_map(|value| value.strip_prefix("did=").map(|did| did.to_string()))
+            .collect::<Vec<String>>();
+
+        match dids.as_slice() {
+            [did] => Ok(did.clone()),
+            [] => Err(anyhow!("no did txt record found")),
+            _ => Err(anyhow!("multiple did txt records found")),
+        }
+    }
+
+    async fn resolve_handle_http(&self, handle: &str) -> Result<String> {
+        let url = format!("https://{}/.well-known/atproto-did", handle);
+
+        let response = self
+            .http_client
+            .get(url)
+            .send()
+            .await
+            .context("atproto-did get failed")?
+            .error_for_status()
+            .context("atproto-did get failed")?;
+        let content = read_limited(response, MAX_IDENTITY_DOCUMENT_SIZE)
+            .await
+            .context("atproto-did read failed")?;
+
+        let did = String::from_utf8(content).context("atproto-did read failed")?;
+        let did = did.trim();
+        if !did.starts_with("did:") {
+            return Err(anyhow!("atproto-did is not a did"));
+        }
+        Ok(did.to_string())
+    }
+
+    async fn resolve_did_document(&self, did: &str) -> Result<DidDocument> {
+        if did.contains(['/', '?', '#', '%']) {
+            return Err(anyhow!("invalid did"));
+        }
+
+        let (http_client, url) = if did.starts_with("did:plc:") {
+            let url = plc_document_url(&self.plc_directory, did)?;
+            (&self.plc_client, url.to_string())
+        } else if let Some(parts) = did.strip_prefix("did:web:") {
+            let mut parts = parts.split(':');
+            let hostname = parts.next().unwrap_or_default();
+            if !is_valid_server(hostname) {
+                return Err(anyhow!("invalid did:web hostname"));
+            }
+            let path = parts.collect::<Vec<&str>>();
+            let url = if path.is_empty() {
+                format!("https://{}/.well-known/did.json", hostname)
+            } else {
+                format!("https://{}/{}/did.json", hostname, path.join("/"))
+            };
+            (&self.http_client, url)
+        } else {
+            return Err(anyhow!("unsupported did method"));
+        };
+
+        let response = http_client
+            .get(url)
+            .send()
+            .await
+            .context("did document get failed")?
+            .error_for_status()
+            .context("did document get failed")?;
+        let content = read_limited(response, MAX_IDENTITY_DOCUMENT_SIZE)
+            .await
+            .context("did document read failed")?;
+        let document: DidDocument =
+            serde_json::from_slice(&content).context("did document parse failed")?;
+
+        if document.id != did {
+            return Err(anyhow!("did document id does not match"));
+        }
+        Ok(document)
+    }
+}
+
+/// Returns the URL of a DID's document in the PLC directory. The DID is added
+/// as a single path segment, so it cannot reach other paths on the directory.
+fn plc_document_url(plc_directory: &str, did: &str) -> Result<reqwest::Url> {
+    let mut url = reqwest::Url::parse(plc_directory).context("invalid plc directory")?;
+    url.path_segments_mut()
+        .map_err(|_| anyhow!("invalid plc directory"))?
+        .pop_if_empty()
+        .push(did);
+    Ok(url)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{plc_document_url, Identity};
+
+    #[test]
+    fn test_plc_document_url() {
+        let url = |plc_directory, did| plc_document_url(plc_directory, did).unwrap().to_string();
+        assert_eq!(
+            url("https://plc.directory", "did:plc:tgudj2fjm77pzkuawquqhsxm"),
+            "https://plc.directory/did:plc:tgudj2fjm77pzkuawquqhsxm"
+        );
+        assert_eq!(
+            url(
+                "http://plc.internal:2582/base/",
+                "did:plc:tgudj2fjm77pzkuawquqhsxm"
+            ),
+            "http://plc.internal:2582/base/did:plc:tgudj2fjm77pzkuawquqhsxm"
+        );
+        assert_eq!(
+            url("https://plc.directory", "did:plc:../../admin"),
+            "https://plc.directory/did:plc:..%2F..%2Fadmin"
+        );
+    }
+
+    #[test]
+    fn test_identity_is() {
+        let identity = Identity {
+            did: Some("did:plc:tgudj2fjm77pzkuawquqhsxm".to_string()),
+            handle: Some("ngerakines.me".to_string()),
+        };
+        assert!(identity.is("did:plc:tgudj2fjm77pzkuawquqhsxm"));
+        assert!(identity.is("ngerakines.me"));
+        assert!(identity.is("NGerakines.me"));
+        assert!(!identity.is("bob.example.com"));
+
+        let identity = Identity::from_authority("Alice.Example.com");
+        assert_eq!(identity.handle, Some("alice.example.com".to_string()));
+        assert!(identity.did.is_none());
+        assert!(identity.is("alice.example.com"));
+    }
+}
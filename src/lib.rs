@@ -1,6 +1,14 @@
 pub mod cache;
 pub mod config;
+pub mod egress;
 pub(crate) mod errors;
 pub mod http;
+pub(crate) mod httpcache;
+pub mod identity;
 pub(crate) mod model;
+pub mod overrides;
+pub mod store;
+pub mod telemetry;
+pub(crate) mod uritemplate;
 pub mod webhostmeta;
+pub(crate) mod xrd;
@@ -1,5 +1,18 @@
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
+
+/// Characters that are percent-encoded when writing the QUERY component of
+/// an AT-URI. The query is kept as given, so existing percent-encoded
+/// triplets are left alone.
+const QUERY: &AsciiSet = &CONTROLS.add(b' ').add(b'"').add(b'<').add(b'>').add(b'`');
+
+/// Characters that are percent-encoded when writing the decoded FRAGMENT
+/// component of an AT-URI.
+const FRAGMENT: &AsciiSet = &QUERY.add(b'#').add(b'%');
+
 /// Represents a parsed AT-URI following the syntax:
-/// AT-URI = "at://" AUTHORITY [ "/" COLLECTION [ "/" RKEY ] ]
+/// AT-URI = "at://" AUTHORITY [ "/" COLLECTION [ "/" RKEY ] ] [ "?" QUERY ] [ "#" FRAGMENT ]
+///
+/// Components other than the query are percent-decoded.
 #[derive(Debug, Clone)]
 pub(crate) struct AtUri {
     /// The AUTHORITY component (handle or DID) from the AT-URI
@@ -8,6 +21,40 @@ pub(crate) struct AtUri {
     pub(crate) collection: Option<String>,
     /// The RKEY component (record key)
     pub(crate) rkey: Option<String>,
+    /// The QUERY component, without the leading `?`. The query is kept as
+    /// given, since decoding it would lose the difference between its
+    /// separators and encoded `&` and `=` characters.
+    pub(crate) query: Option<String>,
+    /// The FRAGMENT component, without the leading `#`, typically a JSON
+    /// pointer to a field within the record
+    pub(crate) fragment: Option<String>,
+}
+
+impl AtUri {
+    /// Returns true if the record key is a TID, the timestamp identifier most
+    /// records are keyed by.
+    pub(crate) fn has_tid_rkey(&self) -> bool {
+        self.rkey.as_deref().is_some_and(is_tid)
+    }
+}
+
+impl std::fmt::Display for AtUri {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "at://{}", self.authority)?;
+        if let Some(collection) = &self.collection {
+            write!(f, "/{}", collection)?;
+            if let Some(rkey) = &self.rkey {
+                write!(f, "/{}", rkey)?;
+            }
+        }
+        if let Some(query) = &self.query {
+            write!(f, "?{}", utf8_percent_encode(query, QUERY))?;
+        }
+        if let Some(fragment) = &self.fragment {
+            write!(f, "#{}", utf8_percent_encode(fragment, FRAGMENT))?;
+        }
+        Ok(())
+    }
 }
 
 pub(crate) fn validate_aturi<S: Into<String>>(aturi: S) -> Option<AtUri> {
@@ -20,30 +67,105 @@ pub(crate) fn validate_aturi<S: Into<String>>(aturi: S) -> Option<AtUri> {
         aturi
     };
 
-    let stripped = aturi.strip_prefix("at://");
-    if stripped.is_none() {
-        return None;
-    }
+    // AT-URIs copied from a URL may be percent-encoded as a whole. They are
+    // decoded once, so their components are not decoded again.
+    let (stripped, decoded) = match aturi.strip_prefix("at://") {
+        Some(stripped) => (stripped.to_string(), false),
+        None => {
+            let decoded = percent_decode(aturi)?;
+            (decoded.strip_prefix("at://")?.to_string(), true)
+        }
+    };
+    let decode = |value: &str| match decoded {
+        true => Some(value.to_string()),
+        false => percent_decode(value),
+    };
+
+    let (stripped, fragment) = match stripped.split_once('#') {
+        Some((stripped, fragment)) => (stripped, Some(fragment)),
+        None => (stripped.as_str(), None),
+    };
+    let (stripped, query) = match stripped.split_once('?') {
+        Some((stripped, query)) => (stripped, Some(query)),
+        None => (stripped, None),
+    };
 
-    let stripped = stripped.unwrap();
+    let parts = stripped
+        .split('/')
+        .map(decode)
+        .collect::<Option<Vec<String>>>()?;
 
-    let parts = stripped.split('/').collect::<Vec<&str>>();
+    // Decoding must not introduce delimiters into a component, where they
+    // would change the meaning of the URLs built from it.
+    if parts.iter().any(|part| part.contains(RESERVED)) {
+        return None;
+    }
 
-    if !parts.is_empty() && !is_valid_authority(parts[0]) {
+    if !parts.is_empty() && !is_valid_authority(&parts[0]) {
         return None;
     }
-    if parts.len() > 1 && !is_valid_nsid(parts[1]) {
+    if parts.len() > 1 && !is_valid_nsid(&parts[1]) {
+        return None;
+    }
+    if parts.len() > 2 && !is_valid_rkey(&parts[2]) {
         return None;
     }
     if parts.len() > 3 {
         return None;
     }
 
-    return Some(AtUri {
+    let fragment = match fragment.filter(|fragment| !fragment.is_empty()) {
+        Some(fragment) => Some(decode(fragment)?),
+        None => None,
+    };
+
+    Some(AtUri {
         authority: parts[0].to_string(),
-        collection: parts.get(1).map(|s| s.to_string()),
-        rkey: parts.get(2).map(|s| s.to_string()),
-    });
+        collection: parts.get(1).cloned(),
+        rkey: parts.get(2).cloned(),
+        query: query.filter(|query| !query.is_empty()).map(str::to_string),
+        fragment,
+    })
+}
+
+/// Characters that may not appear in a decoded authority, collection or rkey.
+const RESERVED: [char; 5] = ['/', '?', '#', '@', '%'];
+
+/// Percent-decodes a component, which must decode to UTF-8.
+fn percent_decode(value: &str) -> Option<String> {
+    percent_decode_str(value)
+        .decode_utf8()
+        .ok()
+        .map(|value| value.into_owned())
+}
+
+/// Validates the RKEY component of an AT-URI: 1 to 512 characters from
+/// `A-Za-z0-9.-_:~`, other than `.` and `..`.
+pub(crate) fn is_valid_rkey(rkey: &str) -> bool {
+    fn is_valid_char(byte: u8) -> bool {
+        byte.is_ascii_alphanumeric()
+            || byte == b'.'
+            || byte == b'-'
+            || byte == b'_'
+            || byte == b':'
+            || byte == b'~'
+    }
+    (1..=512).contains(&rkey.len())
+        && rkey != "."
+        && rkey != ".."
+        && rkey.bytes().all(is_valid_char)
+}
+
+/// Returns true if the value is a TID: 13 characters of base32-sortable
+/// encoding, where the first character leaves the top bit unset.
+pub(crate) fn is_tid(value: &str) -> bool {
+    const ALPHABET: &[u8] = b"234567abcdefghijklmnopqrstuvwxyz";
+    value.len() == 13
+        && value.bytes().all(|byte| ALPHABET.contains(&byte))
+        && value
+            .bytes()
+            .next()
+            .is_some_and(|byte| ALPHABET[..16].contains(&byte))
 }
 
 pub(crate) fn is_valid_nsid(nsid: &str) -> bool {
@@ -83,6 +205,18 @@ pub(crate) fn is_valid_hostname(hostname: &str) -> bool {
         || hostname.len() > 253)
 }
 
+/// Validates a hostname that Hopper makes requests to. Beyond being a valid
+/// hostname, it must have at least two labels and must not end in a numeric
+/// label, which rules out IP address literals.
+pub(crate) fn is_valid_server(hostname: &str) -> bool {
+    is_valid_hostname(hostname)
+        && hostname.contains('.')
+        && hostname
+            .rsplit('.')
+            .next()
+            .is_some_and(|tld| !tld.starts_with(|c: char| c.is_ascii_digit()))
+}
+
 enum InputType {
     Handle(String),
     Plc(String),
@@ -102,9 +236,12 @@ pub(crate) fn is_valid_authority(authority: &str) -> bool {
 
     match authority_type {
         InputType::Handle(handle) => is_valid_hostname(&handle) && handle.chars().any(|c| c == '.'),
-        InputType::Plc(did) => did
-            .strip_prefix("did:plc:")
-            .is_some_and(|remaining| remaining.len() == 24),
+        InputType::Plc(did) => did.strip_prefix("did:plc:").is_some_and(|remaining| {
+            remaining.len() == 24
+                && remaining
+                    .bytes()
+                    .all(|byte| byte.is_ascii_lowercase() || (b'2'..=b'7').contains(&byte))
+        }),
         InputType::Web(did) => {
             let parts = did
                 .strip_prefix("did:web:")
@@ -115,7 +252,183 @@ pub(crate) fn is_valid_authority(authority: &str) -> bool {
                     && inner_parts.first().is_some_and(|hostname| {
                         is_valid_hostname(hostname) && hostname.chars().any(|c| c == '.')
                     })
+                    && inner_parts[1..]
+                        .iter()
+                        .all(|segment| is_valid_did_web_segment(segment))
             })
         }
     }
 }
+
+/// Validates a path segment of a did:web DID, which becomes a segment of the
+/// URL its document is fetched from.
+fn is_valid_did_web_segment(segment: &str) -> bool {
+    !segment.is_empty()
+        && segment != "."
+        && segment != ".."
+        && segment.bytes().all(|byte| {
+            byte.is_ascii_alphanumeric() || byte == b'.' || byte == b'-' || byte == b'_'
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_tid, is_valid_authority, is_valid_rkey, validate_aturi};
+
+    #[test]
+    fn test_is_valid_authority() {
+        let tests = [
+            ("alice.bsky.social", true),
+            ("did:plc:tgudj2fjm77pzkuawquqhsxm", true),
+            ("did:plc:TGUDJ2FJM77PZKUAWQUQHSXM", false),
+            ("did:plc:tgudj2fjm77pzkuawquqhsx1", false),
+            ("did:plc:../../admin/xxxxxxxxxxxx", false),
+            ("did:web:example.com", true),
+            ("did:web:example.com:user:alice", true),
+            ("did:web:example.com:..:admin", false),
+            ("did:web:example.com::alice", false),
+            ("did:web:example.com:a/b", false),
+            ("localhost", false),
+        ];
+        for (authority, expected) in tests {
+            assert_eq!(is_valid_authority(authority), expected, "{}", authority);
+        }
+    }
+
+    #[test]
+    fn test_is_valid_rkey() {
+        let tests = [
+            ("3jui7kd54zh2y", true),
+            ("self", true),
+            ("example.com", true),
+            ("~1.2-3_", true),
+            ("pre:fix", true),
+            ("...", true),
+            ("", false),
+            (".", false),
+            ("..", false),
+            ("alpha/beta", false),
+            ("a b", false),
+            ("a?b", false),
+            ("a#b", false),
+            ("a@b", false),
+            ("a%20b", false),
+        ];
+        for (rkey, expected) in tests {
+            assert_eq!(is_valid_rkey(rkey), expected, "{:?}", rkey);
+        }
+        assert!(is_valid_rkey(&"a".repeat(512)));
+        assert!(!is_valid_rkey(&"a".repeat(513)));
+    }
+
+    #[test]
+    fn test_is_tid() {
+        assert!(is_tid("3jui7kd54zh2y"));
+        assert!(is_tid("2222222222222"));
+        assert!(!is_tid("3jui7kd54zh2"));
+        assert!(!is_tid("3jui7kd54zh2yy"));
+        assert!(!is_tid("3JUI7KD54ZH2Y"));
+        assert!(!is_tid("3jui7kd54zh21"));
+        assert!(!is_tid("zjui7kd54zh2y"));
+    }
+
+    #[test]
+    fn test_validate_aturi_rkey() {
+        let aturi = validate_aturi("at://alice.bsky.social/app.bsky.feed.post/3jui7kd54zh2y");
+        assert!(aturi.is_some_and(|aturi| aturi.has_tid_rkey()));
+
+        let aturi = validate_aturi("at://alice.bsky.social/app.bsky.actor.profile/self");
+        assert!(aturi.is_some_and(|aturi| !aturi.has_tid_rkey()));
+
+        assert!(validate_aturi("at://alice.bsky.social/app.bsky.feed.post").is_some());
+        assert!(validate_aturi("at://alice.bsky.social/app.bsky.feed.post/").is_none());
+        assert!(validate_aturi("at://alice.bsky.social/app.bsky.feed.post/..").is_none());
+        assert!(validate_aturi("at://alice.bsky.social/app.bsky.feed.post/a@b").is_none());
+    }
+
+    #[test]
+    fn test_validate_aturi_query_fragment() {
+        let aturi = validate_aturi(
+            "at://did:plc:tgudj2fjm77pzkuawquqhsxm/app.bsky.feed.post/3jui7kd54zh2y?x=1#/text",
+        )
+        .unwrap();
+        assert_eq!(aturi.rkey.as_deref(), Some("3jui7kd54zh2y"));
+        assert_eq!(aturi.query.as_deref(), Some("x=1"));
+        assert_eq!(aturi.fragment.as_deref(), Some("/text"));
+
+        let aturi = validate_aturi("at://alice.bsky.social#/displayName").unwrap();
+        assert_eq!(aturi.authority, "alice.bsky.social");
+        assert!(aturi.collection.is_none());
+        assert_eq!(aturi.fragment.as_deref(), Some("/displayName"));
+
+        let aturi = validate_aturi("at://alice.bsky.social/app.bsky.feed.post?#").unwrap();
+        assert!(aturi.query.is_none());
+        assert!(aturi.fragment.is_none());
+
+        assert!(validate_aturi("at://alice.bsky.social/app.bsky.feed.post/?x=1").is_none());
+    }
+
+    #[test]
+    fn test_validate_aturi_percent_encoded() {
+        let aturi =
+            validate_aturi("at://did%3Aplc%3Atgudj2fjm77pzkuawquqhsxm/app.bsky.feed.post").unwrap();
+        assert_eq!(aturi.authority, "did:plc:tgudj2fjm77pzkuawquqhsxm");
+
+        let aturi = validate_aturi(
+            "at%3A%2F%2Fdid%3Aplc%3Atgudj2fjm77pzkuawquqhsxm%2Fapp.bsky.feed.post%2F3jui7kd54zh2y",
+        )
+        .unwrap();
+        assert_eq!(aturi.authority, "did:plc:tgudj2fjm77pzkuawquqhsxm");
+        assert_eq!(aturi.collection.as_deref(), Some("app.bsky.feed.post"));
+        assert_eq!(aturi.rkey.as_deref(), Some("3jui7kd54zh2y"));
+
+        let aturi =
+            validate_aturi("at://alice.bsky.social/app.bsky.feed.post/a#/embed%20text").unwrap();
+        assert_eq!(aturi.fragment.as_deref(), Some("/embed text"));
+        assert_eq!(
+            aturi.to_string(),
+            "at://alice.bsky.social/app.bsky.feed.post/a#/embed%20text"
+        );
+
+        assert!(validate_aturi("at://alice.bsky.social/app.bsky.feed.post/a%2Fb").is_none());
+        assert!(validate_aturi("at://alice.bsky.social/app.bsky.feed.post/%FF").is_none());
+
+        // Input encoded as a whole is only decoded once: the rkey is `a%41`.
+        assert!(
+            validate_aturi("at%3A%2F%2Falice.bsky.social%2Fapp.bsky.feed.post%2Fa%2541").is_none()
+        );
+        let aturi =
+            validate_aturi("at%3A%2F%2Falice.bsky.social%2Fapp.bsky.feed.post%2Fa%23%2Fb%2520c")
+                .unwrap();
+        assert_eq!(aturi.fragment.as_deref(), Some("/b%20c"));
+    }
+
+    #[test]
+    fn test_validate_aturi_reserved_after_decoding() {
+        let tests = [
+            "at://did:plc:..%2F..%2Fadmin%2Fxxxxxxxxxxxx/app.bsky.feed.post/a",
+            "at://did%3Aplc%3A..%2F..%2Fadmin%2Fxxxxxxxxxxxx/app.bsky.feed.post",
+            "at://did:web:example.com%3A..%2Fadmin/app.bsky.feed.post",
+            "at://alice.bsky.social%3Fx%3D1/app.bsky.feed.post",
+            "at://alice.bsky.social%23x/app.bsky.feed.post",
+            "at://user%40alice.bsky.social/app.bsky.feed.post",
+            "at://alice.bsky.social/app.bsky%2Ffeed.post",
+            "at://alice.bsky.social/app.bsky.feed.post%3Fx",
+            "at://alice.bsky.social/app.bsky.feed.post/a%2525",
+        ];
+        for aturi in tests {
+            assert!(validate_aturi(aturi).is_none(), "{}", aturi);
+        }
+    }
+
+    #[test]
+    fn test_validate_aturi_query_raw() {
+        let aturi =
+            validate_aturi("at://alice.bsky.social/app.bsky.feed.post/a?a=x%26y&b=1").unwrap();
+        assert_eq!(aturi.query.as_deref(), Some("a=x%26y&b=1"));
+        assert_eq!(
+            aturi.to_string(),
+            "at://alice.bsky.social/app.bsky.feed.post/a?a=x%26y&b=1"
+        );
+    }
+}
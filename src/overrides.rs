@@ -0,0 +1,105 @@
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+
+use crate::{config::OverrideMode, webhostmeta::WebHostMeta};
+
+/// The overrides bundled with Hopper, for providers that do not publish a
+/// host-meta document.
+const BUNDLED: &str = include_str!("../etc/host-meta-overrides.json");
+
+/// Host-meta documents configured by the operator for providers that do not
+/// publish their own, keyed by hostname.
+#[derive(Clone, Default)]
+pub struct HostMetaOverrides {
+    mode: OverrideMode,
+    entries: HashMap<String, WebHostMeta>,
+}
+
+impl HostMetaOverrides {
+    pub fn new(mode: OverrideMode, entries: HashMap<String, WebHostMeta>) -> Self {
+        Self { mode, entries }
+    }
+
+    /// Loads overrides from a JSON file containing an object that maps each
+    /// hostname to a host-meta document.
+    pub fn load(path: &str, mode: OverrideMode) -> Result<Self> {
+        let content = std::fs::read(path).context("reading host-meta overrides failed")?;
+        Self::parse(&content, mode)
+    }
+
+    /// Returns the overrides bundled with Hopper.
+    pub fn bundled(mode: OverrideMode) -> Result<Self> {
+        Self::parse(BUNDLED.as_bytes(), mode)
+    }
+
+    fn parse(content: &[u8], mode: OverrideMode) -> Result<Self> {
+        let entries = serde_json::from_slice::<HashMap<String, WebHostMeta>>(content)
+            .context("parsing host-meta overrides failed")?;
+        Ok(Self::new(mode, entries))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Returns the entry for the hostname when it replaces the remote host-meta.
+    pub(crate) fn replacement(&self, hostname: &str) -> Option<&WebHostMeta> {
+        match self.mode {
+            OverrideMode::Override => self.entries.get(hostname),
+            OverrideMode::Fallback => None,
+        }
+    }
+
+    /// Returns the entry for the hostname when it is used after the remote
+    /// host-meta could not be fetched.
+    pub(crate) fn fallback(&self, hostname: &str) -> Option<&WebHostMeta> {
+        match self.mode {
+            OverrideMode::Override => None,
+            OverrideMode::Fallback => self.entries.get(hostname),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::OverrideMode;
+
+    use super::HostMetaOverrides;
+
+    #[test]
+    fn test_load_bundled_overrides() {
+        let overrides = HostMetaOverrides::load(
+            concat!(env!("CARGO_MANIFEST_DIR"), "/etc/host-meta-overrides.json"),
+            OverrideMode::Override,
+        );
+        assert!(overrides.is_ok());
+
+        let overrides = overrides.unwrap();
+        assert!(overrides.replacement("bsky.app").is_some());
+        assert!(overrides.fallback("bsky.app").is_none());
+        assert!(overrides.replacement("smokesignal.events").is_none());
+    }
+
+    #[test]
+    fn test_bundled_overrides() {
+        let overrides = HostMetaOverrides::bundled(OverrideMode::Override).unwrap();
+        for hostname in ["bsky.app", "frontpage.fyi", "whtwnd.com"] {
+            assert!(overrides.replacement(hostname).is_some(), "{}", hostname);
+        }
+    }
+
+    #[test]
+    fn test_fallback_mode() {
+        let overrides = HostMetaOverrides::load(
+            concat!(env!("CARGO_MANIFEST_DIR"), "/etc/host-meta-overrides.json"),
+            OverrideMode::Fallback,
+        )
+        .unwrap();
+        assert!(overrides.replacement("whtwnd.com").is_none());
+        assert!(overrides.fallback("whtwnd.com").is_some());
+    }
+}
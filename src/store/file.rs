@@ -0,0 +1,244 @@
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use redb::{Database, ReadableTable, TableDefinition};
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use super::{CacheStore, StoreTable};
+
+fn definition(table: StoreTable) -> TableDefinition<'static, &'static str, &'static [u8]> {
+    TableDefinition::new(table.name())
+}
+
+/// Prefixes a value with the time it expires at, in seconds since the epoch.
+fn encode(value: &[u8], expires_at: SystemTime) -> Vec<u8> {
+    let expires_at = expires_at
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let mut content = Vec::with_capacity(8 + value.len());
+    content.extend_from_slice(&expires_at.to_be_bytes());
+    content.extend_from_slice(value);
+    content
+}
+
+/// Returns the value of an encoded entry, or `None` if it has expired or is
+/// not readable.
+fn decode(content: &[u8], now: SystemTime) -> Option<&[u8]> {
+    let (expires_at, value) = content.split_first_chunk::<8>()?;
+    let expires_at = UNIX_EPOCH + Duration::from_secs(u64::from_be_bytes(*expires_at));
+    (expires_at > now).then_some(value)
+}
+
+/// A store kept in a local database file, so that the caches survive
+/// restarts. The whole file is loaded into the caches at startup; expired
+/// entries are removed when loading.
+#[derive(Clone)]
+pub struct FileStore {
+    database: Arc<Database>,
+}
+
+impl FileStore {
+    pub fn open(path: &str) -> Result<Self> {
+        let database = Database::create(path).context("opening cache store failed")?;
+
+        let transaction = database
+            .begin_write()
+            .context("opening cache store failed")?;
+        for table in [
+            StoreTable::WebHostMeta,
+            StoreTable::WebFinger,
+            StoreTable::AtUri,
+        ] {
+            transaction
+                .open_table(definition(table))
+                .context("opening cache store failed")?;
+        }
+        transaction.commit().context("opening cache store failed")?;
+
+        Ok(Self {
+            database: Arc::new(database),
+        })
+    }
+
+    /// Runs a database operation on the blocking thread pool.
+    async fn blocking<T, F>(&self, operation: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Database) -> Result<T> + Send + 'static,
+    {
+        let database = self.database.clone();
+        tokio::task::spawn_blocking(move || operation(&database))
+            .await
+            .map_err(|err| anyhow!("cache store task failed: {}", err))?
+    }
+}
+
+#[async_trait]
+impl CacheStore for FileStore {
+    async fn get(&self, table: StoreTable, key: &str) -> Result<Option<Vec<u8>>> {
+        let key = key.to_string();
+        self.blocking(move |database| {
+            let transaction = database.begin_read()?;
+            let stored = transaction.open_table(definition(table))?;
+            let value = stored.get(key.as_str())?.and_then(|content| {
+                decode(content.value(), SystemTime::now()).map(|value| value.to_vec())
+            });
+            Ok(value)
+        })
+        .await
+        .context("reading cache store failed")
+    }
+
+    async fn insert(
+        &self,
+        table: StoreTable,
+        key: &str,
+        value: Vec<u8>,
+        ttl: Duration,
+    ) -> Result<()> {
+        let key = key.to_string();
+        let content = encode(&value, SystemTime::now() + ttl);
+        self.blocking(move |database| {
+            let transaction = database.begin_write()?;
+            transaction
+                .open_table(definition(table))?
+                .insert(key.as_str(), content.as_slice())?;
+            transaction.commit()?;
+            Ok(())
+        })
+        .await
+        .context("writing cache store failed")
+    }
+
+    async fn remove(&self, table: StoreTable, key: &str) -> Result<()> {
+        let key = key.to_string();
+        self.blocking(move |database| {
+            let transaction = database.begin_write()?;
+            transaction
+                .open_table(definition(table))?
+                .remove(key.as_str())?;
+            transaction.commit()?;
+            Ok(())
+        })
+        .await
+        .context("writing cache store failed")
+    }
+
+    async fn keys(&self, table: StoreTable, prefix: &str) -> Result<Vec<String>> {
+        let prefix = prefix.to_string();
+        self.blocking(move |database| {
+            let now = SystemTime::now();
+            let transaction = database.begin_read()?;
+            let stored = transaction.open_table(definition(table))?;
+            let mut keys = Vec::new();
+            for entry in stored.range(prefix.as_str()..)? {
+                let (key, content) = entry?;
+                if !key.value().starts_with(&prefix) {
+                    break;
+                }
+                if decode(content.value(), now).is_some() {
+                    keys.push(key.value().to_string());
+                }
+            }
+            Ok(keys)
+        })
+        .await
+        .context("reading cache store failed")
+    }
+
+    async fn load(&self, table: StoreTable) -> Result<Vec<(String, Vec<u8>)>> {
+        self.blocking(move |database| {
+            let now = SystemTime::now();
+            let transaction = database.begin_write()?;
+            let mut entries = Vec::new();
+            {
+                let mut stored = transaction.open_table(definition(table))?;
+                stored.retain(|_, content| decode(content, now).is_some())?;
+                for entry in stored.iter()? {
+                    let (key, content) = entry?;
+                    if let Some(value) = decode(content.value(), now) {
+                        entries.push((key.value().to_string(), value.to_vec()));
+                    }
+                }
+            }
+            transaction.commit()?;
+            Ok(entries)
+        })
+        .await
+        .context("loading cache store failed")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::FileStore;
+    use crate::store::{CacheStore, StoreTable};
+
+    #[tokio::test]
+    async fn test_file_store() {
+        let path = std::env::temp_dir().join(format!("hopper-store-{}.redb", std::process::id()));
+        let store = FileStore::open(path.to_str().unwrap()).unwrap();
+
+        store
+            .insert(
+                StoreTable::AtUri,
+                "fresh",
+                b"value".to_vec(),
+                Duration::from_secs(60),
+            )
+            .await
+            .unwrap();
+        store
+            .insert(
+                StoreTable::AtUri,
+                "expired",
+                b"value".to_vec(),
+                Duration::ZERO,
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(
+            store.get(StoreTable::AtUri, "fresh").await.unwrap(),
+            Some(b"value".to_vec())
+        );
+        assert_eq!(store.get(StoreTable::AtUri, "expired").await.unwrap(), None);
+        assert_eq!(
+            store.get(StoreTable::WebHostMeta, "fresh").await.unwrap(),
+            None
+        );
+
+        store
+            .insert(
+                StoreTable::AtUri,
+                "other",
+                b"value".to_vec(),
+                Duration::from_secs(60),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            store.keys(StoreTable::AtUri, "f").await.unwrap(),
+            vec!["fresh".to_string()]
+        );
+        assert_eq!(
+            store.keys(StoreTable::AtUri, "").await.unwrap(),
+            vec!["fresh".to_string(), "other".to_string()]
+        );
+        store.remove(StoreTable::AtUri, "other").await.unwrap();
+
+        let entries = store.load(StoreTable::AtUri).await.unwrap();
+        assert_eq!(entries, vec![("fresh".to_string(), b"value".to_vec())]);
+
+        store.remove(StoreTable::AtUri, "fresh").await.unwrap();
+        assert!(store.load(StoreTable::AtUri).await.unwrap().is_empty());
+
+        drop(store);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,83 @@
+//! Stores that hold resolution cache entries outside of the process, so that
+//! they survive restarts or are shared between instances. The in-memory
+//! caches stay in front of the store: entries are read from the store when
+//! they are missing from memory and written through when they are filled.
+//!
+//! This is deliberate: resolutions are served from memory without a round
+//! trip to the store, at the cost of an instance not seeing changes other
+//! instances make to a shared store while it holds the entry. That window is
+//! bounded by keeping entries in memory for at most
+//! [`SHARED_STORE_MEMORY_TTL`] when the store is shared.
+
+use anyhow::Result;
+use async_trait::async_trait;
+use std::{sync::Arc, time::Duration};
+
+pub mod file;
+pub mod redis;
+
+/// How long entries are kept in memory when the store is shared between
+/// instances, after which they are read from the store again.
+pub const SHARED_STORE_MEMORY_TTL: Duration = Duration::from_secs(60);
+
+/// The cache an entry belongs to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StoreTable {
+    WebHostMeta,
+    WebFinger,
+    AtUri,
+}
+
+impl StoreTable {
+    pub fn name(&self) -> &'static str {
+        match self {
+            StoreTable::WebHostMeta => "webhostmeta",
+            StoreTable::WebFinger => "webfinger",
+            StoreTable::AtUri => "aturi",
+        }
+    }
+}
+
+/// A store for serialized cache entries that expire after a given time.
+#[async_trait]
+pub trait CacheStore: Send + Sync {
+    /// Returns the entry stored under the key, unless it has expired.
+    async fn get(&self, table: StoreTable, key: &str) -> Result<Option<Vec<u8>>>;
+
+    /// Stores an entry that expires after the given time.
+    async fn insert(
+        &self,
+        table: StoreTable,
+        key: &str,
+        value: Vec<u8>,
+        ttl: Duration,
+    ) -> Result<()>;
+
+    /// Removes the entry stored under the key.
+    async fn remove(&self, table: StoreTable, key: &str) -> Result<()>;
+
+    /// Returns the keys of the unexpired entries whose keys start with the
+    /// prefix, used to purge entries the in-memory caches do not hold.
+    async fn keys(&self, table: StoreTable, prefix: &str) -> Result<Vec<String>>;
+
+    /// Returns true if other instances may write to the store.
+    fn is_shared(&self) -> bool {
+        false
+    }
+
+    /// Returns every unexpired entry, used to fill the in-memory caches at
+    /// startup. Stores that are read on demand return nothing.
+    async fn load(&self, _table: StoreTable) -> Result<Vec<(String, Vec<u8>)>> {
+        Ok(Vec::new())
+    }
+}
+
+/// Opens the store at a location, which is either a `redis://` or
+/// `rediss://` URL or the path of a local database file.
+pub async fn open_store(location: &str) -> Result<Arc<dyn CacheStore>> {
+    if location.starts_with("redis://") || location.starts_with("rediss://") {
+        Ok(Arc::new(redis::RedisStore::connect(location).await?))
+    } else {
+        Ok(Arc::new(file::FileStore::open(location)?))
+    }
+}
@@ -0,0 +1,154 @@
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use redis::{aio::ConnectionManager, AsyncCommands};
+use std::time::Duration;
+
+use super::{CacheStore, StoreTable};
+
+/// A store kept in a Redis-protocol server, so that instances share their
+/// caches. Entries expire through the server's own key expiry and are read
+/// on demand rather than loaded at startup.
+#[derive(Clone)]
+pub struct RedisStore {
+    connection: ConnectionManager,
+}
+
+impl RedisStore {
+    pub async fn connect(url: &str) -> Result<Self> {
+        let client = redis::Client::open(url).context("opening cache store failed")?;
+        let connection = ConnectionManager::new(client)
+            .await
+            .context("connecting to cache store failed")?;
+        Ok(Self { connection })
+    }
+}
+
+fn redis_key(table: StoreTable, key: &str) -> String {
+    format!("hopper:{}:{}", table.name(), key)
+}
+
+/// Escapes the characters that are special in a `SCAN` pattern.
+fn escape_pattern(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[async_trait]
+impl CacheStore for RedisStore {
+    async fn get(&self, table: StoreTable, key: &str) -> Result<Option<Vec<u8>>> {
+        self.connection
+            .clone()
+            .get(redis_key(table, key))
+            .await
+            .context("reading cache store failed")
+    }
+
+    async fn insert(
+        &self,
+        table: StoreTable,
+        key: &str,
+        value: Vec<u8>,
+        ttl: Duration,
+    ) -> Result<()> {
+        let seconds = ttl.as_secs();
+        if seconds == 0 {
+            return Ok(());
+        }
+        self.connection
+            .clone()
+            .set_ex(redis_key(table, key), value, seconds)
+            .await
+            .context("writing cache store failed")
+    }
+
+    async fn remove(&self, table: StoreTable, key: &str) -> Result<()> {
+        self.connection
+            .clone()
+            .del(redis_key(table, key))
+            .await
+            .context("writing cache store failed")
+    }
+
+    fn is_shared(&self) -> bool {
+        true
+    }
+
+    async fn keys(&self, table: StoreTable, prefix: &str) -> Result<Vec<String>> {
+        let table_prefix = redis_key(table, "");
+        let pattern = format!("{}*", escape_pattern(&redis_key(table, prefix)));
+        let mut connection = self.connection.clone();
+        let mut iter = connection
+            .scan_match::<_, String>(pattern)
+            .await
+            .context("reading cache store failed")?;
+
+        let mut keys = Vec::new();
+        while let Some(key) = iter.next_item().await {
+            if let Some(key) = key.strip_prefix(&table_prefix) {
+                keys.push(key.to_string());
+            }
+        }
+        keys.sort();
+        keys.dedup();
+        Ok(keys)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{escape_pattern, RedisStore};
+
+    #[test]
+    fn test_escape_pattern() {
+        assert_eq!(
+            escape_pattern("hopper:webfinger:example.com/.well-known/webfinger?"),
+            "hopper:webfinger:example.com/.well-known/webfinger\\?"
+        );
+        assert_eq!(escape_pattern("a*[b]\\"), "a\\*\\[b\\]\\\\");
+    }
+    use crate::store::{CacheStore, StoreTable};
+
+    /// Runs against the server in `TEST_REDIS_URL`, such as a local instance
+    /// at `redis://127.0.0.1:6379`, with `cargo test -- --ignored`.
+    #[tokio::test]
+    #[ignore = "needs a Redis server in TEST_REDIS_URL"]
+    async fn test_redis_store() {
+        let url = std::env::var("TEST_REDIS_URL").expect("TEST_REDIS_URL is not set");
+        let store = RedisStore::connect(&url).await.unwrap();
+        let key = format!("test-{}", std::process::id());
+
+        store
+            .insert(
+                StoreTable::AtUri,
+                &key,
+                b"value".to_vec(),
+                Duration::from_secs(60),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            store.get(StoreTable::AtUri, &key).await.unwrap(),
+            Some(b"value".to_vec())
+        );
+        assert_eq!(
+            store.get(StoreTable::WebHostMeta, &key).await.unwrap(),
+            None
+        );
+
+        assert_eq!(
+            store.keys(StoreTable::AtUri, &key).await.unwrap(),
+            vec![key.clone()]
+        );
+
+        store.remove(StoreTable::AtUri, &key).await.unwrap();
+        assert_eq!(store.get(StoreTable::AtUri, &key).await.unwrap(), None);
+    }
+}
@@ -0,0 +1,157 @@
+//! Metrics about resolution and cache behavior, exported in the Prometheus
+//! text format.
+//!
+//! Servers come from user input, so metrics are only labeled with the servers
+//! given at startup; every other server is labeled `other` to keep the number
+//! of series bounded.
+
+use anyhow::{anyhow, Result};
+use metrics::{counter, describe_counter, describe_gauge, describe_histogram, gauge, histogram};
+use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
+use std::{collections::HashSet, sync::OnceLock, time::Duration};
+
+use crate::errors::DocumentError;
+
+const RESOLUTIONS: &str = "hopper_resolutions_total";
+const RESOLUTION_DURATION: &str = "hopper_resolution_duration_seconds";
+const MATCHES: &str = "hopper_matches_total";
+const HOST_META_FETCHES: &str = "hopper_host_meta_fetches_total";
+const HOST_META_FETCH_DURATION: &str = "hopper_host_meta_fetch_duration_seconds";
+const CACHE_LOOKUPS: &str = "hopper_cache_lookups_total";
+const CACHE_ENTRIES: &str = "hopper_cache_entries";
+
+/// The label used for servers that were not given at startup.
+const OTHER_SERVER: &str = "other";
+
+/// Histogram buckets in seconds, covering the HTTP client timeouts.
+const DURATION_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+pub(crate) const CACHE_WEBHOSTMETA: &str = "webhostmeta";
+pub(crate) const CACHE_WEBFINGER: &str = "webfinger";
+pub(crate) const CACHE_ATURI: &str = "aturi";
+
+static LABELED_SERVERS: OnceLock<HashSet<String>> = OnceLock::new();
+
+/// Installs the global metrics recorder, labeling metrics with the given
+/// servers. The returned handle renders the metrics and must have its upkeep
+/// run periodically.
+pub fn install_recorder(servers: &[String]) -> Result<PrometheusHandle> {
+    let _ = LABELED_SERVERS.set(servers.iter().cloned().collect());
+
+    let handle = PrometheusBuilder::new()
+        .set_buckets(DURATION_BUCKETS)
+        .and_then(|builder| builder.install_recorder())
+        .map_err(|err| anyhow!("installing metrics recorder failed: {}", err))?;
+
+    describe_counter!(RESOLUTIONS, "AT-URI resolutions by outcome.");
+    describe_histogram!(
+        RESOLUTION_DURATION,
+        metrics::Unit::Seconds,
+        "Time taken to resolve AT-URIs, including cached resolutions."
+    );
+    describe_counter!(MATCHES, "AT-URI resolutions that matched, by server.");
+    describe_counter!(
+        HOST_META_FETCHES,
+        "Host-meta and WebFinger fetches by server and outcome."
+    );
+    describe_histogram!(
+        HOST_META_FETCH_DURATION,
+        metrics::Unit::Seconds,
+        "Time taken to fetch host-meta and WebFinger documents."
+    );
+    describe_counter!(CACHE_LOOKUPS, "Cache lookups by cache and result.");
+    describe_gauge!(CACHE_ENTRIES, "Entries held in memory by cache.");
+
+    Ok(handle)
+}
+
+/// Returns the label for a server.
+fn server_label(server: &str) -> String {
+    match LABELED_SERVERS.get() {
+        Some(servers) if servers.contains(server) => server.to_string(),
+        _ => OTHER_SERVER.to_string(),
+    }
+}
+
+pub(crate) fn record_resolution(outcome: &'static str, server: Option<&str>, elapsed: Duration) {
+    counter!(RESOLUTIONS, "outcome" => outcome).increment(1);
+    histogram!(RESOLUTION_DURATION).record(elapsed);
+    if let Some(server) = server {
+        counter!(MATCHES, "server" => server_label(server)).increment(1);
+    }
+}
+
+pub(crate) fn record_fetch(server: &str, outcome: &'static str, elapsed: Duration) {
+    counter!(HOST_META_FETCHES, "server" => server_label(server), "outcome" => outcome)
+        .increment(1);
+    histogram!(HOST_META_FETCH_DURATION).record(elapsed);
+}
+
+pub(crate) fn record_cache_lookup(cache: &'static str, hit: bool) {
+    let result = if hit { "hit" } else { "miss" };
+    counter!(CACHE_LOOKUPS, "cache" => cache, "result" => result).increment(1);
+}
+
+pub(crate) fn record_cache_entries(cache: &'static str, entries: u64) {
+    gauge!(CACHE_ENTRIES, "cache" => cache).set(entries as f64);
+}
+
+/// Classifies a failed fetch by the request or document error behind it.
+/// Other failures are documents that could not be parsed.
+pub(crate) fn fetch_error_class(err: &anyhow::Error) -> &'static str {
+    if let Some(err) = err.downcast_ref::<DocumentError>() {
+        return match err {
+            DocumentError::TooLarge(_) => "too-large",
+            DocumentError::UnsupportedContentType(_) => "content-type",
+            DocumentError::TooManyLinks(_) => "too-many-links",
+            DocumentError::TemplateTooLong(_) => "template-too-long",
+        };
+    }
+    let Some(err) = err
+        .chain()
+        .find_map(|err| err.downcast_ref::<reqwest::Error>())
+    else {
+        return "parse";
+    };
+    if err.is_timeout() {
+        "timeout"
+    } else if err.is_connect() {
+        "connect"
+    } else if err.is_status() {
+        "status"
+    } else if err.is_body() || err.is_decode() {
+        "body"
+    } else {
+        "request"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::{anyhow, Context};
+
+    use super::{fetch_error_class, server_label, LABELED_SERVERS};
+    use crate::errors::DocumentError;
+
+    #[test]
+    fn test_server_label() {
+        let _ = LABELED_SERVERS.set(["bsky.app".to_string()].into_iter().collect());
+        assert_eq!(server_label("bsky.app"), "bsky.app");
+        assert_eq!(server_label("example.com"), "other");
+    }
+
+    #[test]
+    fn test_fetch_error_class() {
+        let err = serde_json::from_str::<u32>("{")
+            .context("https://example.com/.well-known/host-meta.json parse failed")
+            .unwrap_err();
+        assert_eq!(fetch_error_class(&err), "parse");
+        assert_eq!(fetch_error_class(&anyhow!("failed")), "parse");
+
+        let err = anyhow::Error::new(DocumentError::TooLarge(1024))
+            .context("https://example.com/.well-known/host-meta.json read failed");
+        assert_eq!(fetch_error_class(&err), "too-large");
+    }
+}
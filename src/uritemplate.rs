@@ -0,0 +1,324 @@
+//! URI template expansion following RFC 6570 levels 1 through 3.
+//!
+//! Expressions use the simple (`{var}`), reserved (`{+var}`), fragment
+//! (`{#var}`), label (`{.var}`), path segment (`{/var}`), path parameter
+//! (`{;var}`), query (`{?var}`) and query continuation (`{&var}`) operators.
+//! Level 4 value modifiers are not supported.
+//!
+//! Variables in path-like expressions are required: expansion fails when one
+//! of them is undefined, so a template never produces a URL that silently
+//! drops a path segment. Variables in `#`, `;`, `?` and `&` expressions are
+//! optional and omitted when undefined, as RFC 6570 specifies.
+
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use std::collections::HashMap;
+
+/// Characters that are percent-encoded by simple expansion, leaving only the
+/// RFC 3986 unreserved characters as-is.
+const UNRESERVED: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// Characters that are percent-encoded by reserved expansion, leaving the
+/// unreserved and reserved characters as-is.
+const UNRESERVED_AND_RESERVED: &AsciiSet = &UNRESERVED
+    .remove(b':')
+    .remove(b'/')
+    .remove(b'?')
+    .remove(b'#')
+    .remove(b'[')
+    .remove(b']')
+    .remove(b'@')
+    .remove(b'!')
+    .remove(b'$')
+    .remove(b'&')
+    .remove(b'\'')
+    .remove(b'(')
+    .remove(b')')
+    .remove(b'*')
+    .remove(b'+')
+    .remove(b',')
+    .remove(b';')
+    .remove(b'=');
+
+/// The expression operators supported at level 3.
+const OPERATORS: [char; 7] = ['+', '#', '.', '/', ';', '?', '&'];
+
+struct Operator {
+    first: &'static str,
+    separator: &'static str,
+    named: bool,
+    if_empty: &'static str,
+    allow_reserved: bool,
+    optional: bool,
+}
+
+impl Operator {
+    fn parse(value: Option<char>) -> &'static Operator {
+        const SIMPLE: Operator = Operator::new("", ",", false, "", false, false);
+        const RESERVED: Operator = Operator::new("", ",", false, "", true, false);
+        const FRAGMENT: Operator = Operator::new("#", ",", false, "", true, true);
+        const LABEL: Operator = Operator::new(".", ".", false, "", false, false);
+        const PATH: Operator = Operator::new("/", "/", false, "", false, false);
+        const PARAMETER: Operator = Operator::new(";", ";", true, "", false, true);
+        const QUERY: Operator = Operator::new("?", "&", true, "=", false, true);
+        const CONTINUATION: Operator = Operator::new("&", "&", true, "=", false, true);
+
+        match value {
+            Some('+') => &RESERVED,
+            Some('#') => &FRAGMENT,
+            Some('.') => &LABEL,
+            Some('/') => &PATH,
+            Some(';') => &PARAMETER,
+            Some('?') => &QUERY,
+            Some('&') => &CONTINUATION,
+            _ => &SIMPLE,
+        }
+    }
+
+    const fn new(
+        first: &'static str,
+        separator: &'static str,
+        named: bool,
+        if_empty: &'static str,
+        allow_reserved: bool,
+        optional: bool,
+    ) -> Self {
+        Self {
+            first,
+            separator,
+            named,
+            if_empty,
+            allow_reserved,
+            optional,
+        }
+    }
+
+    fn encode(&self, value: &str) -> String {
+        if self.allow_reserved {
+            encode_reserved(value)
+        } else {
+            utf8_percent_encode(value, UNRESERVED).to_string()
+        }
+    }
+}
+
+/// Encodes a value for reserved expansion. Existing percent-encoded triplets
+/// are passed through unchanged.
+fn encode_reserved(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut result = String::with_capacity(value.len());
+    let mut start = 0;
+    let mut index = 0;
+    while index < bytes.len() {
+        if bytes[index] == b'%'
+            && bytes.get(index + 1).is_some_and(u8::is_ascii_hexdigit)
+            && bytes.get(index + 2).is_some_and(u8::is_ascii_hexdigit)
+        {
+            result.extend(utf8_percent_encode(
+                &value[start..index],
+                UNRESERVED_AND_RESERVED,
+            ));
+            result.push_str(&value[index..index + 3]);
+            index += 3;
+            start = index;
+        } else {
+            index += 1;
+        }
+    }
+    result.extend(utf8_percent_encode(&value[start..], UNRESERVED_AND_RESERVED));
+    result
+}
+
+fn is_valid_varname(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || byte == b'_' || byte == b'.')
+}
+
+/// Splits a template into literal and expression parts. Returns `None` if
+/// the template has unbalanced braces.
+fn parse(template: &str) -> Option<Vec<(bool, &str)>> {
+    let mut parts = Vec::new();
+    let mut remaining = template;
+    while let Some(start) = remaining.find(['{', '}']) {
+        if remaining[start..].starts_with('}') {
+            return None;
+        }
+        let end = remaining[start..].find('}')? + start;
+        if start > 0 {
+            parts.push((false, &remaining[..start]));
+        }
+        parts.push((true, &remaining[start + 1..end]));
+        remaining = &remaining[end + 1..];
+    }
+    if !remaining.is_empty() {
+        parts.push((false, remaining));
+    }
+    Some(parts)
+}
+
+/// Returns the names of the variables referenced by the template.
+pub(crate) fn variable_names(template: &str) -> Vec<&str> {
+    parse(template)
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|(is_expression, _)| *is_expression)
+        .flat_map(|(_, expression)| {
+            expression
+                .trim_start_matches(OPERATORS)
+                .split(',')
+        })
+        .collect()
+}
+
+/// Expands the template with the given variables. Returns `None` if the
+/// template is malformed or a required variable is undefined.
+pub(crate) fn expand(template: &str, variables: &HashMap<&str, String>) -> Option<String> {
+    let mut result = String::with_capacity(template.len());
+
+    for (is_expression, part) in parse(template)? {
+        if !is_expression {
+            result.push_str(part);
+            continue;
+        }
+
+        let (operator, names) = match part.chars().next() {
+            Some(value) if OPERATORS.contains(&value) => (Operator::parse(Some(value)), &part[1..]),
+            _ => (Operator::parse(None), part),
+        };
+
+        let mut expanded = Vec::new();
+        for name in names.split(',') {
+            if !is_valid_varname(name) {
+                return None;
+            }
+
+            let Some(value) = variables.get(name) else {
+                if operator.optional {
+                    continue;
+                }
+                return None;
+            };
+
+            let value = operator.encode(value);
+            expanded.push(match (operator.named, value.is_empty()) {
+                (true, true) => format!("{}{}", name, operator.if_empty),
+                (true, false) => format!("{}={}", name, value),
+                (false, _) => value,
+            });
+        }
+
+        if !expanded.is_empty() {
+            result.push_str(operator.first);
+            result.push_str(&expanded.join(operator.separator));
+        }
+    }
+
+    Some(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{expand, variable_names};
+
+    fn variables() -> HashMap<&'static str, String> {
+        HashMap::from([
+            ("var", "value".to_string()),
+            ("hello", "Hello World!".to_string()),
+            ("path", "/foo/bar".to_string()),
+            ("empty", "".to_string()),
+            ("x", "1024".to_string()),
+            ("y", "768".to_string()),
+            ("did", "did:plc:tgudj2fjm77pzkuawquqhsxm".to_string()),
+            ("_x", "".to_string()),
+        ])
+    }
+
+    #[test]
+    fn test_expand_rfc6570_examples() {
+        let variables = variables();
+        let cases = [
+            ("{var}", "value"),
+            ("{hello}", "Hello%20World%21"),
+            ("{+var}", "value"),
+            ("{+hello}", "Hello%20World!"),
+            ("{+path}/here", "/foo/bar/here"),
+            ("here?ref={+path}", "here?ref=/foo/bar"),
+            ("X{#var}", "X#value"),
+            ("X{#hello}", "X#Hello%20World!"),
+            ("map?{x,y}", "map?1024,768"),
+            ("{x,hello,y}", "1024,Hello%20World%21,768"),
+            ("{+x,hello,y}", "1024,Hello%20World!,768"),
+            ("{+path,x}/here", "/foo/bar,1024/here"),
+            ("{#x,hello,y}", "#1024,Hello%20World!,768"),
+            ("X{.var}", "X.value"),
+            ("X{.x,y}", "X.1024.768"),
+            ("{/var}", "/value"),
+            ("{/var,x}/here", "/value/1024/here"),
+            ("{;x,y}", ";x=1024;y=768"),
+            ("{;x,y,empty}", ";x=1024;y=768;empty"),
+            ("{?x,y}", "?x=1024&y=768"),
+            ("{?x,y,empty}", "?x=1024&y=768&empty="),
+            ("?fixed=yes{&x}", "?fixed=yes&x=1024"),
+            ("{did}", "did%3Aplc%3Atgudj2fjm77pzkuawquqhsxm"),
+            ("{+did}", "did:plc:tgudj2fjm77pzkuawquqhsxm"),
+            ("{+hello}%2F", "Hello%20World!%2F"),
+            ("{+path}{_x}", "/foo/bar"),
+        ];
+        for (template, expected) in cases {
+            assert_eq!(
+                expand(template, &variables),
+                Some(expected.to_string()),
+                "{}",
+                template
+            );
+        }
+    }
+
+    #[test]
+    fn test_expand_undefined() {
+        let variables = variables();
+
+        // Required variables make the expansion fail
+        assert_eq!(expand("https://example.com/{rkey}", &variables), None);
+        assert_eq!(expand("https://example.com{/var,rkey}", &variables), None);
+
+        // Fragment and query variables are omitted
+        assert_eq!(
+            expand("https://example.com/{var}{#rkey}", &variables),
+            Some("https://example.com/value".to_string())
+        );
+        assert_eq!(
+            expand("https://example.com/{var}{?rkey}", &variables),
+            Some("https://example.com/value".to_string())
+        );
+        assert_eq!(
+            expand("https://example.com/{var}{?x,rkey}", &variables),
+            Some("https://example.com/value?x=1024".to_string())
+        );
+    }
+
+    #[test]
+    fn test_expand_malformed() {
+        let variables = variables();
+        assert_eq!(expand("https://example.com/{var", &variables), None);
+        assert_eq!(expand("https://example.com/var}", &variables), None);
+        assert_eq!(expand("https://example.com/{}", &variables), None);
+        assert_eq!(expand("https://example.com/{var:3}", &variables), None);
+        assert_eq!(expand("https://example.com/{var*}", &variables), None);
+    }
+
+    #[test]
+    fn test_variable_names() {
+        assert_eq!(
+            variable_names("https://example.com/{authority}{/collection,rkey}{?did}"),
+            vec!["authority", "collection", "rkey", "did"]
+        );
+    }
+}
@@ -1,24 +1,43 @@
 use anyhow::{Context, Result};
-use serde::Deserialize;
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant, SystemTime},
+};
 
-use crate::model::AtUri;
+use crate::{
+    errors::DocumentError, httpcache, identity::Identity, model::AtUri, telemetry, uritemplate,
+    xrd::parse_xrd,
+};
 
 pub const REL_LINK: &str = "https://hopper.at/rel/link";
 pub const NS_AUTHORITY: &str = "https://atproto.com/ns/authority";
 pub const NS_COLLECTION: &str = "https://atproto.com/ns/collection";
 pub const NS_RKEY: &str = "https://atproto.com/ns/rkey";
+pub const NS_PRIORITY: &str = "https://hopper.at/ns/priority";
 
-#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+/// The largest host-meta or WebFinger document that is read.
+const MAX_DOCUMENT_SIZE: usize = 256 * 1024;
+
+/// The most links a document may have.
+const MAX_LINKS: usize = 256;
+
+/// The longest link template, in bytes.
+const MAX_TEMPLATE_LENGTH: usize = 2048;
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
 pub struct Link {
     pub(crate) rel: String,
     pub(crate) template: Option<String>,
 
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) href: Option<String>,
+
     #[serde(default)]
     pub(crate) properties: HashMap<String, String>,
 }
 
-#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
 pub struct WebHostMeta {
     #[serde(default)]
     pub(crate) properties: HashMap<String, String>,
@@ -27,17 +46,266 @@ pub struct WebHostMeta {
     pub(crate) links: Vec<Link>,
 }
 
-pub(crate) async fn query(http_client: &reqwest::Client, hostname: &str) -> Result<WebHostMeta> {
+/// How a fetched document is parsed.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub(crate) enum Format {
+    /// A JSON (JRD) document.
+    Json,
+    /// An XRD document, or a JSON document if the response says so.
+    Xrd,
+}
+
+impl Format {
+    fn accept(&self) -> &'static str {
+        match self {
+            Format::Json => "application/jrd+json, application/json;q=0.9",
+            Format::Xrd => {
+                "application/xrd+xml, application/jrd+json;q=0.9, application/json;q=0.8"
+            }
+        }
+    }
+}
+
+/// A fetched host-meta or WebFinger document, along with the response
+/// metadata needed to cache and revalidate it.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct Fetched {
+    pub(crate) webhostmeta: WebHostMeta,
+    pub(crate) url: String,
+    pub(crate) format: Format,
+    /// The freshness lifetime given by the response caching headers.
+    pub(crate) max_age: Option<Duration>,
+    pub(crate) etag: Option<String>,
+    pub(crate) last_modified: Option<String>,
+}
+
+/// Fetches the host-meta document for a hostname. The JSON document at
+/// `/.well-known/host-meta.json` is tried first, falling back to the XRD
+/// document at `/.well-known/host-meta` defined by RFC 6415 when the server
+/// does not serve a usable JSON document.
+pub(crate) async fn query(http_client: &reqwest::Client, hostname: &str) -> Result<Fetched> {
     let url = format!("https://{}/.well-known/host-meta.json", hostname,);
+    match fetch(http_client, url, Format::Json, None).await {
+        Ok(fetched) => Ok(fetched),
+        Err(err) if falls_back_to_xrd(&err) => {
+            tracing::debug!(error = ?err, hostname, "host-meta.json query failed");
+            let url = format!("https://{}/.well-known/host-meta", hostname,);
+            fetch(http_client, url, Format::Xrd, None).await
+        }
+        Err(err) => Err(err),
+    }
+}
 
-    http_client
-        .get(url)
+/// Returns true if a failed host-meta.json query means the server has no
+/// JSON document: it is missing, is not JSON or does not parse. Other
+/// failures, such as timeouts, would most likely happen again.
+fn falls_back_to_xrd(err: &anyhow::Error) -> bool {
+    if let Some(DocumentError::UnsupportedContentType(_)) = err.downcast_ref() {
+        return true;
+    }
+    err.chain().any(|err| {
+        if err.is::<serde_json::Error>() {
+            return true;
+        }
+        err.downcast_ref::<reqwest::Error>()
+            .and_then(reqwest::Error::status)
+            .is_some_and(|status| {
+                status == reqwest::StatusCode::NOT_FOUND || status == reqwest::StatusCode::GONE
+            })
+    })
+}
+
+/// Fetches the WebFinger (RFC 7033) document for a resource from a hostname,
+/// asking only for Hopper links.
+pub(crate) async fn query_webfinger(
+    http_client: &reqwest::Client,
+    hostname: &str,
+    resource: &str,
+) -> Result<Fetched> {
+    let url = reqwest::Url::parse_with_params(
+        &format!("https://{}/.well-known/webfinger", hostname,),
+        &[("resource", resource), ("rel", REL_LINK)],
+    )
+    .context("webfinger url invalid")?;
+    fetch(http_client, url.to_string(), Format::Json, None).await
+}
+
+/// Fetches a previously fetched document again, using its `ETag` and
+/// `Last-Modified` validators. A `304 Not Modified` response keeps the
+/// document and refreshes its caching metadata.
+pub(crate) async fn revalidate(
+    http_client: &reqwest::Client,
+    fetched: &Fetched,
+) -> Result<Fetched> {
+    fetch(
+        http_client,
+        fetched.url.clone(),
+        fetched.format,
+        Some(fetched),
+    )
+    .await
+}
+
+/// Fetches a document, recording how long it took and how it went.
+async fn fetch(
+    http_client: &reqwest::Client,
+    url: String,
+    format: Format,
+    previous: Option<&Fetched>,
+) -> Result<Fetched> {
+    let server = reqwest::Url::parse(&url)
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_string))
+        .unwrap_or_default();
+    let started = Instant::now();
+    let fetched = fetch_document(http_client, url, format, previous).await;
+    let outcome = match &fetched {
+        Ok(_) => "ok",
+        Err(err) => telemetry::fetch_error_class(err),
+    };
+    telemetry::record_fetch(&server, outcome, started.elapsed());
+    fetched
+}
+
+async fn fetch_document(
+    http_client: &reqwest::Client,
+    url: String,
+    format: Format,
+    previous: Option<&Fetched>,
+) -> Result<Fetched> {
+    let mut request = http_client
+        .get(&url)
+        .header(reqwest::header::ACCEPT, format.accept());
+    if let Some(etag) = previous.and_then(|previous| previous.etag.as_ref()) {
+        request = request.header(reqwest::header::IF_NONE_MATCH, etag);
+    }
+    if let Some(last_modified) = previous.and_then(|previous| previous.last_modified.as_ref()) {
+        request = request.header(reqwest::header::IF_MODIFIED_SINCE, last_modified);
+    }
+
+    let response = request
         .send()
         .await
-        .context("web host meta get failed")?
-        .json()
+        .with_context(|| format!("{} get failed", url))?;
+
+    let headers = response.headers();
+    let max_age = httpcache::max_age(headers, SystemTime::now());
+    let header = |name| {
+        headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string)
+    };
+    let etag = header(reqwest::header::ETAG);
+    let last_modified = header(reqwest::header::LAST_MODIFIED);
+    let content_type = header(reqwest::header::CONTENT_TYPE);
+
+    if let Some(previous) = previous
+        && response.status() == reqwest::StatusCode::NOT_MODIFIED
+    {
+        return Ok(revalidated(previous, max_age, etag, last_modified));
+    }
+
+    let response = response
+        .error_for_status()
+        .with_context(|| format!("{} get failed", url))?;
+
+    let is_json = is_json_content_type(format, content_type.as_deref())
+        .with_context(|| format!("{} rejected", url))?;
+
+    let content = read_limited(response, MAX_DOCUMENT_SIZE)
         .await
-        .context("web host meta parse failed")
+        .with_context(|| format!("{} read failed", url))?;
+
+    let webhostmeta = if is_json {
+        serde_json::from_slice(&content).with_context(|| format!("{} parse failed", url))?
+    } else {
+        let content =
+            String::from_utf8(content).with_context(|| format!("{} parse failed", url))?;
+        parse_xrd(&content).with_context(|| format!("{} parse failed", url))?
+    };
+    check_limits(&webhostmeta).with_context(|| format!("{} rejected", url))?;
+
+    Ok(Fetched {
+        webhostmeta,
+        url,
+        format,
+        max_age,
+        etag,
+        last_modified,
+    })
+}
+
+/// Returns the previous document updated with the headers of a `304 Not
+/// Modified` response. Headers the response leaves out keep their previous
+/// values, so a bare 304 keeps the previous freshness lifetime as RFC 9111
+/// section 4.3.4 describes.
+fn revalidated(
+    previous: &Fetched,
+    max_age: Option<Duration>,
+    etag: Option<String>,
+    last_modified: Option<String>,
+) -> Fetched {
+    Fetched {
+        max_age: max_age.or(previous.max_age),
+        etag: etag.or_else(|| previous.etag.clone()),
+        last_modified: last_modified.or_else(|| previous.last_modified.clone()),
+        ..previous.clone()
+    }
+}
+
+/// Returns whether a document with the content type is parsed as JSON, or
+/// an error if the content type is not one the format accepts. Documents
+/// fetched as JSON must have a JSON content type; documents fetched as XRD
+/// may have either.
+fn is_json_content_type(format: Format, content_type: Option<&str>) -> Result<bool, DocumentError> {
+    let media_type = content_type
+        .and_then(|value| value.split(';').next())
+        .map(|value| value.trim().to_lowercase())
+        .unwrap_or_default();
+    match (format, media_type.as_str()) {
+        (_, "application/jrd+json" | "application/json") => Ok(true),
+        (Format::Xrd, "application/xrd+xml" | "application/xml" | "text/xml") => Ok(false),
+        _ => Err(DocumentError::UnsupportedContentType(media_type)),
+    }
+}
+
+/// Reads a response body, failing as soon as it is larger than `limit`
+/// bytes rather than buffering all of it.
+pub(crate) async fn read_limited(mut response: reqwest::Response, limit: usize) -> Result<Vec<u8>> {
+    if response
+        .content_length()
+        .is_some_and(|length| length > limit as u64)
+    {
+        return Err(DocumentError::TooLarge(limit).into());
+    }
+
+    let mut content = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        if content.len() + chunk.len() > limit {
+            return Err(DocumentError::TooLarge(limit).into());
+        }
+        content.extend_from_slice(&chunk);
+    }
+    Ok(content)
+}
+
+/// Rejects documents with more links, or longer link templates, than are
+/// kept in the cache.
+fn check_limits(webhostmeta: &WebHostMeta) -> Result<(), DocumentError> {
+    if webhostmeta.links.len() > MAX_LINKS {
+        return Err(DocumentError::TooManyLinks(MAX_LINKS));
+    }
+    let too_long = webhostmeta.links.iter().any(|link| {
+        [link.template.as_ref(), link.href.as_ref()]
+            .into_iter()
+            .flatten()
+            .any(|template| template.len() > MAX_TEMPLATE_LENGTH)
+    });
+    if too_long {
+        return Err(DocumentError::TemplateTooLong(MAX_TEMPLATE_LENGTH));
+    }
+    Ok(())
 }
 
 impl Link {
@@ -48,9 +316,16 @@ impl Link {
         Self {
             rel: REL_LINK.to_string(),
             template: Some(template.to_string()),
+            href: None,
             properties,
         }
     }
+
+    /// Returns the link's URI template. WebFinger links carry it in `href`
+    /// rather than `template`; `template` wins when both are present.
+    pub(crate) fn uri_template(&self) -> Option<&str> {
+        self.template.as_deref().or(self.href.as_deref())
+    }
 }
 
 impl WebHostMeta {
@@ -61,18 +336,39 @@ impl WebHostMeta {
         }
     }
 
-    pub(crate) fn match_uri(&self, server: &str, aturi: &AtUri) -> Option<String> {
+    /// Returns true if any link depends on the resolved identity of the AT-URI
+    /// authority.
+    pub(crate) fn needs_identity(&self) -> bool {
+        self.links.iter().any(|link| {
+            link.rel == REL_LINK
+                && (link.properties.contains_key(NS_AUTHORITY)
+                    || link.uri_template().is_some_and(|template| {
+                        uritemplate::variable_names(template)
+                            .iter()
+                            .any(|name| *name == "did" || *name == "handle")
+                    }))
+        })
+    }
+
+    /// Returns the expanded destination and the link that produced it for the
+    /// link that matches the AT-URI most specifically. See `Specificity` for
+    /// how links are ranked. Ties go to the first link.
+    pub(crate) fn match_uri(
+        &self,
+        server: &str,
+        aturi: &AtUri,
+        identity: &Identity,
+    ) -> Option<(String, &Link)> {
         let prefix = format!("https://{}/", server);
+        let mut best: Option<(Specificity, String, &Link)> = None;
         for link in &self.links {
             if link.rel != REL_LINK {
                 continue;
             }
 
-            if link.template.is_none() {
+            let Some(template) = link.uri_template() else {
                 continue;
-            }
-
-            let template = link.template.as_ref().unwrap();
+            };
 
             if !template.starts_with(prefix.as_str()) {
                 continue;
@@ -81,22 +377,26 @@ impl WebHostMeta {
             // Property-based matching: if a property is present, the corresponding
             // URI component must match the property value.
 
-            // Check NS_AUTHORITY: if present, authority must match
-            if let Some(required_authority) = link.properties.get(NS_AUTHORITY) {
-                if &aturi.authority != required_authority {
-                    continue;
-                }
+            // Check NS_AUTHORITY: if present, authority must match either the
+            // authority as given or its resolved DID or handle
+            if let Some(required_authority) = link.properties.get(NS_AUTHORITY)
+                && &aturi.authority != required_authority
+                && !identity.is(required_authority)
+            {
+                continue;
             }
 
-            // Check NS_COLLECTION: if present, collection must match
-            if let Some(required_collection) = link.properties.get(NS_COLLECTION) {
-                match &aturi.collection {
-                    Some(collection) if collection == required_collection => {
-                        // Match, continue checking
+            // Check NS_COLLECTION: if present, collection must match one of the
+            // listed NSIDs or NSID wildcards
+            let collection_match = match link.properties.get(NS_COLLECTION) {
+                Some(required_collection) => {
+                    match match_collection(required_collection, aturi.collection.as_deref()) {
+                        Some(collection_match) => collection_match,
+                        None => continue, // No match, skip this link
                     }
-                    _ => continue, // No match, skip this link
                 }
-            }
+                None => CollectionMatch::Any,
+            };
 
             // Check NS_RKEY: if present, rkey must match
             if let Some(required_rkey) = link.properties.get(NS_RKEY) {
@@ -108,29 +408,362 @@ impl WebHostMeta {
                 }
             }
 
-            // Template variable substitution:
+            // Template expansion (RFC 6570) with the following variables:
             // {authority} - The AUTHORITY component from the AT-URI (handle or DID)
             // {collection} - The COLLECTION component (NSID)
             // {rkey} - The RKEY component (record key)
-            let mut result = template.replace("{authority}", &aturi.authority);
+            // {did} - The DID of the authority
+            // {handle} - The verified handle of the authority
+            // {query} - The QUERY component of the AT-URI, as given
+            // {fragment} - The FRAGMENT component of the AT-URI
+            // {uri} - The full AT-URI
+            // Links that reference a variable without a value are skipped.
+            let mut variables = HashMap::from([
+                ("authority", aturi.authority.clone()),
+                ("uri", aturi.to_string()),
+            ]);
             if let Some(collection) = &aturi.collection {
-                result = result.replace("{collection}", collection);
+                variables.insert("collection", collection.clone());
+            }
+            if let Some(rkey) = &aturi.rkey {
+                variables.insert("rkey", rkey.clone());
+            }
+            if let Some(query) = &aturi.query {
+                variables.insert("query", query.clone());
+            }
+            if let Some(fragment) = &aturi.fragment {
+                variables.insert("fragment", fragment.clone());
             }
-            if let Some(nsid) = &aturi.rkey {
-                result = result.replace("{rkey}", nsid);
+            if let Some(did) = &identity.did {
+                variables.insert("did", did.clone());
             }
+            if let Some(handle) = &identity.handle {
+                variables.insert("handle", handle.clone());
+            }
+
+            let Some(result) = uritemplate::expand(template, &variables) else {
+                continue;
+            };
 
-            return Some(result);
+            if !is_valid_destination(server, &result) {
+                tracing::debug!(server, destination = result, "invalid destination");
+                continue;
+            }
+
+            let specificity = Specificity {
+                filters: [NS_AUTHORITY, NS_COLLECTION, NS_RKEY]
+                    .iter()
+                    .filter(|property| link.properties.contains_key(**property))
+                    .count(),
+                collection: collection_match,
+                priority: link
+                    .properties
+                    .get(NS_PRIORITY)
+                    .and_then(|value| value.trim().parse().ok())
+                    .unwrap_or_default(),
+            };
+
+            if best
+                .as_ref()
+                .is_none_or(|(best_specificity, _, _)| specificity > *best_specificity)
+            {
+                best = Some((specificity, result, link));
+            }
         }
-        None
+        best.map(|(_, result, link)| (result, link))
     }
 }
 
+/// Returns true if an expanded template is an HTTPS URL on the server itself,
+/// without a port or user information. The URL must also be unchanged by
+/// parsing, which rules out values that reshape it, such as `..` segments
+/// that would be resolved away or backslashes read as slashes.
+fn is_valid_destination(server: &str, destination: &str) -> bool {
+    let Ok(url) = reqwest::Url::parse(destination) else {
+        return false;
+    };
+    url.scheme() == "https"
+        && url
+            .host_str()
+            .is_some_and(|host| host.eq_ignore_ascii_case(server))
+        && url.port().is_none()
+        && url.username().is_empty()
+        && url.password().is_none()
+        && url.as_str() == destination
+}
+
+/// How specifically a link matched an AT-URI. Fields are compared in order:
+/// links with more filter properties (authority, collection and rkey) win,
+/// then links with a more precise collection match, then links with a
+/// higher priority property.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct Specificity {
+    filters: usize,
+    collection: CollectionMatch,
+    priority: i64,
+}
+
+/// How specifically a link's collection property matched a collection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum CollectionMatch {
+    /// The link has no collection property.
+    Any,
+    /// A wildcard matched, holding the length of the matched prefix so that
+    /// longer prefixes are more specific.
+    Wildcard(usize),
+    /// The collection is listed exactly.
+    Exact,
+}
+
+/// Matches a collection against a collection property value. The value is a
+/// comma separated list of NSIDs and NSID wildcards such as `app.bsky.feed.*`.
+fn match_collection(required: &str, collection: Option<&str>) -> Option<CollectionMatch> {
+    let collection = collection?;
+    required
+        .split(',')
+        .map(str::trim)
+        .filter_map(|pattern| {
+            if pattern == collection {
+                Some(CollectionMatch::Exact)
+            } else if pattern == "*" {
+                Some(CollectionMatch::Wildcard(0))
+            } else {
+                pattern
+                    .strip_suffix('*')
+                    .filter(|prefix| prefix.ends_with('.') && collection.starts_with(prefix))
+                    .map(|prefix| CollectionMatch::Wildcard(prefix.len()))
+            }
+        })
+        .max()
+}
+
 #[cfg(test)]
 mod tests {
-    use std::collections::HashMap;
+    use anyhow::{anyhow, Context};
+    use std::{collections::HashMap, time::Duration};
+
+    use crate::identity::Identity;
+
+    use super::{
+        check_limits, falls_back_to_xrd, is_json_content_type, is_valid_destination, revalidated,
+        Fetched, Format, Link, WebHostMeta, MAX_LINKS, MAX_TEMPLATE_LENGTH,
+    };
+    use crate::errors::DocumentError;
+
+    #[test]
+    fn test_falls_back_to_xrd() {
+        let status_error = |status: u16| {
+            let response = axum::http::Response::builder()
+                .status(status)
+                .body("")
+                .unwrap();
+            let err = reqwest::Response::from(response)
+                .error_for_status()
+                .unwrap_err();
+            anyhow::Error::from(err).context("host-meta.json get failed")
+        };
+        assert!(falls_back_to_xrd(&status_error(404)));
+        assert!(falls_back_to_xrd(&status_error(410)));
+        assert!(!falls_back_to_xrd(&status_error(500)));
+        assert!(!falls_back_to_xrd(&status_error(403)));
+
+        let content_type = anyhow::Error::from(DocumentError::UnsupportedContentType(
+            "text/html".to_string(),
+        ))
+        .context("host-meta.json rejected");
+        assert!(falls_back_to_xrd(&content_type));
+
+        let too_large =
+            anyhow::Error::from(DocumentError::TooLarge(1)).context("host-meta.json read failed");
+        assert!(!falls_back_to_xrd(&too_large));
+
+        let parse = serde_json::from_str::<WebHostMeta>("<XRD/>")
+            .context("host-meta.json parse failed")
+            .unwrap_err();
+        assert!(falls_back_to_xrd(&parse));
+
+        assert!(!falls_back_to_xrd(&anyhow!("host-meta.json get failed")));
+    }
+
+    #[test]
+    fn test_is_json_content_type() {
+        let tests = [
+            (Format::Json, Some("application/jrd+json"), Some(true)),
+            (
+                Format::Json,
+                Some("application/json; charset=utf-8"),
+                Some(true),
+            ),
+            (Format::Json, Some("Application/JSON"), Some(true)),
+            (Format::Json, Some("application/xrd+xml"), None),
+            (Format::Json, Some("text/html"), None),
+            (Format::Json, None, None),
+            (Format::Xrd, Some("application/xrd+xml"), Some(false)),
+            (Format::Xrd, Some("text/xml; charset=utf-8"), Some(false)),
+            (Format::Xrd, Some("application/jrd+json"), Some(true)),
+            (Format::Xrd, Some("text/plain"), None),
+        ];
+        for (format, content_type, expected) in tests {
+            assert_eq!(
+                is_json_content_type(format, content_type).ok(),
+                expected,
+                "{:?} {:?}",
+                format,
+                content_type
+            );
+        }
+    }
+
+    #[test]
+    fn test_revalidated() {
+        let previous = Fetched {
+            webhostmeta: WebHostMeta {
+                properties: HashMap::new(),
+                links: vec![],
+            },
+            url: "https://example.com/.well-known/host-meta.json".to_string(),
+            format: Format::Json,
+            max_age: Some(Duration::from_secs(60)),
+            etag: Some("\"v1\"".to_string()),
+            last_modified: None,
+        };
+
+        // A bare 304 keeps the previous freshness lifetime and validators.
+        let fetched = revalidated(&previous, None, None, None);
+        assert_eq!(fetched.max_age, Some(Duration::from_secs(60)));
+        assert_eq!(fetched.etag.as_deref(), Some("\"v1\""));
+
+        let fetched = revalidated(
+            &previous,
+            Some(Duration::from_secs(300)),
+            Some("\"v2\"".to_string()),
+            None,
+        );
+        assert_eq!(fetched.max_age, Some(Duration::from_secs(300)));
+        assert_eq!(fetched.etag.as_deref(), Some("\"v2\""));
+    }
+
+    #[test]
+    fn test_check_limits() {
+        let link = Link::new("https://example.com/{authority}", None);
+        let webhostmeta = WebHostMeta {
+            properties: HashMap::new(),
+            links: vec![link.clone(); MAX_LINKS],
+        };
+        assert!(check_limits(&webhostmeta).is_ok());
+
+        let webhostmeta = WebHostMeta {
+            properties: HashMap::new(),
+            links: vec![link; MAX_LINKS + 1],
+        };
+        assert!(matches!(
+            check_limits(&webhostmeta),
+            Err(DocumentError::TooManyLinks(_))
+        ));
+
+        let template = format!("https://example.com/{}", "a".repeat(MAX_TEMPLATE_LENGTH));
+        let webhostmeta = WebHostMeta {
+            properties: HashMap::new(),
+            links: vec![Link::new(&template, None)],
+        };
+        assert!(matches!(
+            check_limits(&webhostmeta),
+            Err(DocumentError::TemplateTooLong(_))
+        ));
+    }
+
+    #[test]
+    fn test_is_valid_destination() {
+        let tests = [
+            ("https://bsky.app/profile/alice.bsky.social", true),
+            ("https://bsky.app/profile/alice?tab=posts#top", true),
+            ("https://bsky.app/post/3l%2F..%2F", true),
+            ("https://bsky.app/post/../admin", false),
+            ("https://bsky.app/post/./x", false),
+            ("https://bsky.app/post/%2e%2e/admin", false),
+            ("https://bsky.app/post\\x", false),
+            ("https://bsky.app:8443/post", false),
+            ("https://bsky.app:443/post", false),
+            ("https://user@bsky.app/post", false),
+            ("https://bsky.app@evil.example/post", false),
+            ("https://evil.example/post", false),
+            ("http://bsky.app/post", false),
+            ("https://bsky.app/post with space", false),
+            ("not a url", false),
+        ];
+        for (destination, expected) in tests {
+            assert_eq!(
+                is_valid_destination("bsky.app", destination),
+                expected,
+                "{}",
+                destination
+            );
+        }
+    }
+
+    #[test]
+    fn test_match_uri_invalid_destination() {
+        let webhostmeta = WebHostMeta {
+            properties: HashMap::new(),
+            links: vec![Link::new("https://bsky.app/post/{+rkey}", None)],
+        };
+        let aturi = |rkey: &str| crate::model::AtUri {
+            authority: "alice.bsky.social".to_string(),
+            collection: Some("app.bsky.feed.post".to_string()),
+            rkey: Some(rkey.to_string()),
+            query: None,
+            fragment: None,
+        };
+
+        let matched = webhostmeta.match_uri("bsky.app", &aturi("3l"), &Identity::default());
+        assert_eq!(
+            matched.map(|(destination, _)| destination),
+            Some("https://bsky.app/post/3l".to_string())
+        );
+
+        for rkey in ["../../admin", "..", "x/./y"] {
+            let matched = webhostmeta.match_uri("bsky.app", &aturi(rkey), &Identity::default());
+            assert!(matched.is_none(), "{}", rkey);
+        }
+    }
+
+    #[test]
+    fn test_match_uri_query_fragment() {
+        let webhostmeta = WebHostMeta {
+            properties: HashMap::new(),
+            links: vec![Link::new("https://example.com/{rkey}{#fragment}", None)],
+        };
+        let aturi = |query: Option<&str>, fragment: Option<&str>| crate::model::AtUri {
+            authority: "alice.bsky.social".to_string(),
+            collection: Some("app.bsky.feed.post".to_string()),
+            rkey: Some("3l".to_string()),
+            query: query.map(str::to_string),
+            fragment: fragment.map(str::to_string),
+        };
+        let destination = |webhostmeta: &WebHostMeta, aturi| {
+            webhostmeta
+                .match_uri("example.com", &aturi, &Identity::default())
+                .map(|(destination, _)| destination)
+        };
+
+        assert_eq!(
+            destination(&webhostmeta, aturi(None, Some("/text"))),
+            Some("https://example.com/3l#/text".to_string())
+        );
+        assert_eq!(
+            destination(&webhostmeta, aturi(None, None)),
+            Some("https://example.com/3l".to_string())
+        );
 
-    use super::{Link, WebHostMeta};
+        let webhostmeta = WebHostMeta {
+            properties: HashMap::new(),
+            links: vec![Link::new("https://example.com/{rkey}?{+query}", None)],
+        };
+        assert_eq!(
+            destination(&webhostmeta, aturi(Some("a=x%26y&b=1"), None)),
+            Some("https://example.com/3l?a=x%26y&b=1".to_string())
+        );
+    }
 
     #[test]
     fn test_deserialize() {
@@ -154,6 +787,31 @@ mod tests {
         assert_eq!(webfinger.links.len(), 1);
     }
 
+    #[test]
+    fn test_deserialize_webfinger() {
+        let webfinger =
+            serde_json::from_str::<WebHostMeta>(include_str!("../etc/webfinger.json")).unwrap();
+        assert_eq!(webfinger.links.len(), 1);
+        assert!(webfinger.links[0].template.is_none());
+
+        let aturi = crate::model::AtUri {
+            authority: "did:plc:tgudj2fjm77pzkuawquqhsxm".to_string(),
+            collection: Some("community.lexicon.calendar.event".to_string()),
+            rkey: Some("3lbjhtyyhuk2v".to_string()),
+            query: None,
+            fragment: None,
+        };
+        assert_eq!(
+            webfinger
+                .match_uri("smokesignal.events", &aturi, &Default::default())
+                .map(|(destination, _)| destination),
+            Some(
+                "https://smokesignal.events/did%3Aplc%3Atgudj2fjm77pzkuawquqhsxm/3lbjhtyyhuk2v"
+                    .to_string()
+            )
+        );
+    }
+
     #[test]
     fn test_match_uri_no_collection_filter() {
         let hostname = "smokesignal.events".to_string();
@@ -161,6 +819,7 @@ mod tests {
             links: vec![Link {
                 rel: "https://hopper.at/rel/link".to_string(),
                 template: Some("https://smokesignal.events/profile/{authority}".to_string()),
+                href: None,
                 properties: HashMap::new(), // No collection filter
             }],
             properties: Default::default(),
@@ -174,8 +833,12 @@ mod tests {
                     authority: "ngerakines.me".to_string(),
                     collection: None,
                     rkey: None,
-                }
-            ),
+                    query: None,
+                    fragment: None,
+                },
+                &Default::default(),
+            )
+            .map(|(destination, _)| destination),
             Some("https://smokesignal.events/profile/ngerakines.me".into())
         );
 
@@ -187,8 +850,12 @@ mod tests {
                     authority: "smokesignal.events".to_string(),
                     collection: Some("app.bsky.feed.post".into()),
                     rkey: Some("s0xnr5kqnp".into()),
-                }
-            ),
+                    query: None,
+                    fragment: None,
+                },
+                &Default::default(),
+            )
+            .map(|(destination, _)| destination),
             Some("https://smokesignal.events/profile/smokesignal.events".into())
         );
     }
@@ -200,6 +867,7 @@ mod tests {
             links: vec![Link {
                 rel: "https://hopper.at/rel/link".to_string(),
                 template: Some("https://example.com/{authority}/posts/{rkey}".to_string()),
+                href: None,
                 properties: HashMap::from([(
                     super::NS_COLLECTION.into(),
                     "app.bsky.feed.post".into(),
@@ -216,8 +884,12 @@ mod tests {
                     authority: "alice.example.com".to_string(),
                     collection: Some("app.bsky.feed.post".into()),
                     rkey: Some("abc123".into()),
-                }
-            ),
+                    query: None,
+                    fragment: None,
+                },
+                &Default::default(),
+            )
+            .map(|(destination, _)| destination),
             Some("https://example.com/alice.example.com/posts/abc123".into())
         );
 
@@ -229,8 +901,12 @@ mod tests {
                     authority: "alice.example.com".to_string(),
                     collection: Some("app.bsky.feed.like".into()),
                     rkey: Some("abc123".into()),
-                }
-            ),
+                    query: None,
+                    fragment: None,
+                },
+                &Default::default(),
+            )
+            .map(|(destination, _)| destination),
             None,
         );
 
@@ -242,8 +918,12 @@ mod tests {
                     authority: "alice.example.com".to_string(),
                     collection: None,
                     rkey: None,
-                }
-            ),
+                    query: None,
+                    fragment: None,
+                },
+                &Default::default(),
+            )
+            .map(|(destination, _)| destination),
             None,
         );
     }
@@ -255,6 +935,7 @@ mod tests {
             links: vec![Link {
                 rel: "https://hopper.at/rel/link".to_string(),
                 template: Some("https://example.com/special/{authority}".to_string()),
+                href: None,
                 properties: HashMap::from([(
                     super::NS_AUTHORITY.into(),
                     "alice.example.com".into(),
@@ -271,8 +952,12 @@ mod tests {
                     authority: "alice.example.com".to_string(),
                     collection: None,
                     rkey: None,
-                }
-            ),
+                    query: None,
+                    fragment: None,
+                },
+                &Default::default(),
+            )
+            .map(|(destination, _)| destination),
             Some("https://example.com/special/alice.example.com".into())
         );
 
@@ -284,8 +969,12 @@ mod tests {
                     authority: "bob.example.com".to_string(),
                     collection: None,
                     rkey: None,
-                }
-            ),
+                    query: None,
+                    fragment: None,
+                },
+                &Default::default(),
+            )
+            .map(|(destination, _)| destination),
             None,
         );
     }
@@ -297,6 +986,7 @@ mod tests {
             links: vec![Link {
                 rel: "https://hopper.at/rel/link".to_string(),
                 template: Some("https://example.com/pinned".to_string()),
+                href: None,
                 properties: HashMap::from([(super::NS_RKEY.into(), "pinned".into())]),
             }],
             properties: Default::default(),
@@ -310,8 +1000,12 @@ mod tests {
                     authority: "alice.example.com".to_string(),
                     collection: Some("app.bsky.feed.post".into()),
                     rkey: Some("pinned".into()),
-                }
-            ),
+                    query: None,
+                    fragment: None,
+                },
+                &Default::default(),
+            )
+            .map(|(destination, _)| destination),
             Some("https://example.com/pinned".into())
         );
 
@@ -323,8 +1017,12 @@ mod tests {
                     authority: "alice.example.com".to_string(),
                     collection: Some("app.bsky.feed.post".into()),
                     rkey: Some("abc123".into()),
-                }
-            ),
+                    query: None,
+                    fragment: None,
+                },
+                &Default::default(),
+            )
+            .map(|(destination, _)| destination),
             None,
         );
 
@@ -336,8 +1034,12 @@ mod tests {
                     authority: "alice.example.com".to_string(),
                     collection: Some("app.bsky.feed.post".into()),
                     rkey: None,
-                }
-            ),
+                    query: None,
+                    fragment: None,
+                },
+                &Default::default(),
+            )
+            .map(|(destination, _)| destination),
             None,
         );
     }
@@ -349,6 +1051,7 @@ mod tests {
             links: vec![Link {
                 rel: "https://hopper.at/rel/link".to_string(),
                 template: Some("https://example.com/{authority}/{collection}/{rkey}".to_string()),
+                href: None,
                 properties: HashMap::new(), // No filters
             }],
             properties: Default::default(),
@@ -362,8 +1065,12 @@ mod tests {
                     authority: "alice.example.com".to_string(),
                     collection: Some("app.bsky.feed.post".into()),
                     rkey: Some("abc123".into()),
-                }
-            ),
+                    query: None,
+                    fragment: None,
+                },
+                &Default::default(),
+            )
+            .map(|(destination, _)| destination),
             Some("https://example.com/alice.example.com/app.bsky.feed.post/abc123".into())
         );
 
@@ -375,9 +1082,323 @@ mod tests {
                     authority: "bob.example.com".to_string(),
                     collection: Some("app.bsky.feed.like".into()),
                     rkey: Some("xyz789".into()),
-                }
-            ),
+                    query: None,
+                    fragment: None,
+                },
+                &Default::default(),
+            )
+            .map(|(destination, _)| destination),
             Some("https://example.com/bob.example.com/app.bsky.feed.like/xyz789".into())
         );
     }
+
+    #[test]
+    fn test_match_uri_authority_filter_identity() {
+        let hostname = "example.com".to_string();
+        let web_finger = WebHostMeta {
+            links: vec![Link {
+                rel: "https://hopper.at/rel/link".to_string(),
+                template: Some("https://example.com/special/{authority}".to_string()),
+                href: None,
+                properties: HashMap::from([(
+                    super::NS_AUTHORITY.into(),
+                    "alice.example.com".into(),
+                )]),
+            }],
+            properties: Default::default(),
+        };
+        assert!(web_finger.needs_identity());
+
+        let aturi = crate::model::AtUri {
+            authority: "did:plc:tgudj2fjm77pzkuawquqhsxm".to_string(),
+            collection: None,
+            rkey: None,
+            query: None,
+            fragment: None,
+        };
+
+        // Should match: the DID resolves to the handle in the filter
+        assert_eq!(
+            web_finger
+                .match_uri(
+                    &hostname,
+                    &aturi,
+                    &Identity {
+                        did: Some("did:plc:tgudj2fjm77pzkuawquqhsxm".to_string()),
+                        handle: Some("alice.example.com".to_string()),
+                    },
+                )
+                .map(|(destination, _)| destination),
+            Some("https://example.com/special/did%3Aplc%3Atgudj2fjm77pzkuawquqhsxm".into())
+        );
+
+        // Should NOT match: the DID has no verified handle
+        assert_eq!(
+            web_finger
+                .match_uri(
+                    &hostname,
+                    &aturi,
+                    &Identity::from_authority(&aturi.authority),
+                )
+                .map(|(destination, _)| destination),
+            None,
+        );
+    }
+
+    #[test]
+    fn test_match_uri_identity_variables() {
+        let hostname = "example.com".to_string();
+        let web_finger = WebHostMeta {
+            links: vec![
+                Link::new("https://example.com/did/{+did}/{rkey}", None),
+                Link::new("https://example.com/handle/{handle}", None),
+                Link::new("https://example.com/open?uri={uri}", None),
+            ],
+            properties: Default::default(),
+        };
+        assert!(web_finger.needs_identity());
+
+        let aturi = crate::model::AtUri {
+            authority: "alice.example.com".to_string(),
+            collection: Some("app.bsky.feed.post".into()),
+            rkey: Some("abc123".into()),
+            query: None,
+            fragment: None,
+        };
+
+        // Should use the DID link when the DID is known
+        assert_eq!(
+            web_finger
+                .match_uri(
+                    &hostname,
+                    &aturi,
+                    &Identity {
+                        did: Some("did:plc:tgudj2fjm77pzkuawquqhsxm".to_string()),
+                        handle: Some("alice.example.com".to_string()),
+                    },
+                )
+                .map(|(destination, _)| destination),
+            Some("https://example.com/did/did:plc:tgudj2fjm77pzkuawquqhsxm/abc123".into())
+        );
+
+        // Should skip the DID link when the DID is unknown
+        assert_eq!(
+            web_finger
+                .match_uri(&hostname, &aturi, &Identity::from_authority(&aturi.authority))
+                .map(|(destination, _)| destination),
+            Some("https://example.com/handle/alice.example.com".into())
+        );
+
+        // Should fall through to the {uri} link
+        assert_eq!(
+            web_finger
+                .match_uri(&hostname, &aturi, &Identity::default())
+                .map(|(destination, _)| destination),
+            Some(
+                "https://example.com/open?uri=at%3A%2F%2Falice.example.com%2Fapp.bsky.feed.post%2Fabc123"
+                    .into()
+            )
+        );
+    }
+
+    #[test]
+    fn test_match_uri_missing_variable() {
+        let hostname = "bsky.app".to_string();
+        let web_finger = WebHostMeta::new(vec![
+            Link::new("https://bsky.app/profile/{authority}/post/{rkey}", None),
+            Link::new("https://bsky.app/profile/{authority}", None),
+        ]);
+
+        // Should skip the post link: the AT-URI has no rkey
+        assert_eq!(
+            web_finger
+                .match_uri(
+                    &hostname,
+                    &crate::model::AtUri {
+                        authority: "ngerakines.me".to_string(),
+                        collection: None,
+                        rkey: None,
+                        query: None,
+                        fragment: None,
+                    },
+                    &Default::default(),
+                )
+                .map(|(destination, _)| destination),
+            Some("https://bsky.app/profile/ngerakines.me".into())
+        );
+
+        // Should percent-encode values that are not unreserved characters
+        assert_eq!(
+            web_finger
+                .match_uri(
+                    &hostname,
+                    &crate::model::AtUri {
+                        authority: "did:plc:tgudj2fjm77pzkuawquqhsxm".to_string(),
+                        collection: Some("app.bsky.feed.post".into()),
+                        rkey: Some("a?b#c".into()),
+                        query: None,
+                        fragment: None,
+                    },
+                    &Default::default(),
+                )
+                .map(|(destination, _)| destination),
+            Some(
+                "https://bsky.app/profile/did%3Aplc%3Atgudj2fjm77pzkuawquqhsxm/post/a%3Fb%23c"
+                    .into()
+            )
+        );
+    }
+
+    #[test]
+    fn test_match_uri_collection_wildcard() {
+        let hostname = "example.com".to_string();
+        let web_finger = WebHostMeta::new(vec![
+            Link::new("https://example.com/profile/{authority}", None),
+            Link::new(
+                "https://example.com/calendar/{authority}/{rkey}",
+                Some("community.lexicon.calendar.*"),
+            ),
+            Link::new(
+                "https://example.com/feed/{authority}/{rkey}",
+                Some("app.bsky.feed.*, app.bsky.graph.list"),
+            ),
+            Link::new(
+                "https://example.com/post/{authority}/{rkey}",
+                Some("app.bsky.feed.post"),
+            ),
+        ]);
+
+        let match_collection = |collection: &str| {
+            web_finger
+                .match_uri(
+                    &hostname,
+                    &crate::model::AtUri {
+                        authority: "alice.example.com".to_string(),
+                        collection: Some(collection.to_string()),
+                        rkey: Some("abc123".into()),
+                        query: None,
+                        fragment: None,
+                    },
+                    &Default::default(),
+                )
+                .map(|(destination, _)| destination)
+        };
+
+        // Should match the wildcard over the catch-all link
+        assert_eq!(
+            match_collection("community.lexicon.calendar.rsvp"),
+            Some("https://example.com/calendar/alice.example.com/abc123".into())
+        );
+
+        // Should match the exact collection over the wildcard
+        assert_eq!(
+            match_collection("app.bsky.feed.post"),
+            Some("https://example.com/post/alice.example.com/abc123".into())
+        );
+
+        // Should match the wildcard in a list
+        assert_eq!(
+            match_collection("app.bsky.feed.like"),
+            Some("https://example.com/feed/alice.example.com/abc123".into())
+        );
+
+        // Should match the exact collection in a list
+        assert_eq!(
+            match_collection("app.bsky.graph.list"),
+            Some("https://example.com/feed/alice.example.com/abc123".into())
+        );
+
+        // Should NOT match the wildcard: the prefix must end on a segment
+        assert_eq!(
+            match_collection("app.bsky.feedback.post"),
+            Some("https://example.com/profile/alice.example.com".into())
+        );
+    }
+
+    #[test]
+    fn test_match_uri_specificity() {
+        let hostname = "example.com".to_string();
+        let web_finger = WebHostMeta::new(vec![
+            Link::new("https://example.com/profile/{authority}", None),
+            Link::new(
+                "https://example.com/post/{authority}/{rkey}",
+                Some("app.bsky.feed.post"),
+            ),
+            Link {
+                rel: "https://hopper.at/rel/link".to_string(),
+                template: Some("https://example.com/alice/pinned".to_string()),
+                href: None,
+                properties: HashMap::from([
+                    (super::NS_AUTHORITY.into(), "alice.example.com".into()),
+                    (super::NS_COLLECTION.into(), "app.bsky.feed.post".into()),
+                    (super::NS_RKEY.into(), "pinned".into()),
+                ]),
+            },
+        ]);
+
+        let match_aturi = |collection: Option<&str>, rkey: Option<&str>| {
+            web_finger
+                .match_uri(
+                    &hostname,
+                    &crate::model::AtUri {
+                        authority: "alice.example.com".to_string(),
+                        collection: collection.map(str::to_string),
+                        rkey: rkey.map(str::to_string),
+                        query: None,
+                        fragment: None,
+                    },
+                    &Default::default(),
+                )
+                .map(|(destination, _)| destination)
+        };
+
+        // Should match the catch-all link
+        assert_eq!(
+            match_aturi(None, None),
+            Some("https://example.com/profile/alice.example.com".into())
+        );
+
+        // Should match the collection link over the earlier catch-all link
+        assert_eq!(
+            match_aturi(Some("app.bsky.feed.post"), Some("abc123")),
+            Some("https://example.com/post/alice.example.com/abc123".into())
+        );
+
+        // Should match the authority, collection and rkey link
+        assert_eq!(
+            match_aturi(Some("app.bsky.feed.post"), Some("pinned")),
+            Some("https://example.com/alice/pinned".into())
+        );
+    }
+
+    #[test]
+    fn test_match_uri_priority() {
+        let hostname = "example.com".to_string();
+        let mut preferred = Link::new("https://example.com/b/{authority}", None);
+        preferred
+            .properties
+            .insert(super::NS_PRIORITY.into(), "10".into());
+        let web_finger = WebHostMeta::new(vec![
+            Link::new("https://example.com/a/{authority}", None),
+            preferred,
+        ]);
+
+        // Should match the higher priority link over the earlier link
+        assert_eq!(
+            web_finger
+                .match_uri(
+                    &hostname,
+                    &crate::model::AtUri {
+                        authority: "alice.example.com".to_string(),
+                        collection: None,
+                        rkey: None,
+                        query: None,
+                        fragment: None,
+                    },
+                    &Default::default(),
+                )
+                .map(|(destination, _)| destination),
+            Some("https://example.com/b/alice.example.com".into())
+        );
+    }
 }
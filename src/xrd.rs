@@ -0,0 +1,166 @@
+use anyhow::{anyhow, Context, Result};
+use quick_xml::{
+    escape::resolve_predefined_entity,
+    events::{BytesStart, Event},
+    Reader,
+};
+use std::collections::HashMap;
+
+use crate::webhostmeta::{Link, WebHostMeta};
+
+/// Returns the unescaped value of the attribute with the given local name.
+fn attribute(element: &BytesStart, name: &[u8]) -> Result<Option<String>> {
+    for attribute in element.attributes() {
+        let attribute = attribute.context("xrd attribute parse failed")?;
+        if attribute.key.local_name().as_ref() == name {
+            let value = attribute
+                .unescape_value()
+                .context("xrd attribute parse failed")?;
+            return Ok(Some(value.into_owned()));
+        }
+    }
+    Ok(None)
+}
+
+fn new_link(element: &BytesStart) -> Result<Link> {
+    Ok(Link {
+        rel: attribute(element, b"rel")?.unwrap_or_default(),
+        template: attribute(element, b"template")?,
+        href: attribute(element, b"href")?,
+        properties: HashMap::new(),
+    })
+}
+
+/// Parses an XRD document, as served from `/.well-known/host-meta`, into the
+/// same structure as a host-meta JSON document. `Link` elements and their
+/// `Property` children are kept; properties with `xsi:nil="true"` and other
+/// elements are ignored.
+pub(crate) fn parse_xrd(content: &str) -> Result<WebHostMeta> {
+    let mut reader = Reader::from_str(content);
+
+    let mut webhostmeta = WebHostMeta::new(Vec::new());
+    let mut link: Option<Link> = None;
+    // The property type and value, or `None` for the value of a nil property.
+    let mut property: Option<(String, Option<String>)> = None;
+    let mut found_xrd = false;
+
+    loop {
+        match reader.read_event().context("xrd parse failed")? {
+            Event::Start(element) => match element.local_name().as_ref() {
+                b"XRD" => found_xrd = true,
+                b"Link" => link = Some(new_link(&element)?),
+                b"Property" => {
+                    let property_type = attribute(&element, b"type")?.unwrap_or_default();
+                    let nil = attribute(&element, b"nil")?.is_some_and(|nil| nil == "true");
+                    property = Some((property_type, (!nil).then(String::new)));
+                }
+                _ => {}
+            },
+            Event::Empty(element) if element.local_name().as_ref() == b"Link" => {
+                webhostmeta.links.push(new_link(&element)?);
+            }
+            Event::Text(text) => {
+                if let Some((_, Some(value))) = property.as_mut() {
+                    value.push_str(&text.xml_content().context("xrd text parse failed")?);
+                }
+            }
+            Event::CData(text) => {
+                if let Some((_, Some(value))) = property.as_mut() {
+                    value.push_str(&text.decode().context("xrd text parse failed")?);
+                }
+            }
+            Event::GeneralRef(reference) => {
+                if let Some((_, Some(value))) = property.as_mut() {
+                    if let Some(character) = reference
+                        .resolve_char_ref()
+                        .context("xrd reference parse failed")?
+                    {
+                        value.push(character);
+                    } else {
+                        let name = reference.decode().context("xrd reference parse failed")?;
+                        let resolved = resolve_predefined_entity(&name)
+                            .ok_or_else(|| anyhow!("xrd reference {} is not defined", name))?;
+                        value.push_str(resolved);
+                    }
+                }
+            }
+            Event::End(element) => match element.local_name().as_ref() {
+                b"Link" => {
+                    if let Some(link) = link.take() {
+                        webhostmeta.links.push(link);
+                    }
+                }
+                b"Property" => {
+                    if let Some((property_type, Some(value))) = property.take() {
+                        let value = value.trim().to_string();
+                        match link.as_mut() {
+                            Some(link) => link.properties.insert(property_type, value),
+                            None => webhostmeta.properties.insert(property_type, value),
+                        };
+                    }
+                }
+                _ => {}
+            },
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+
+    if !found_xrd {
+        return Err(anyhow!("xrd document has no XRD element"));
+    }
+
+    Ok(webhostmeta)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_xrd;
+    use crate::webhostmeta::{NS_COLLECTION, REL_LINK};
+
+    #[test]
+    fn test_parse_xrd() {
+        let webhostmeta = parse_xrd(
+            r##"<?xml version="1.0" encoding="UTF-8"?>
+<XRD xmlns="http://docs.oasis-open.org/ns/xri/xrd-1.0"
+     xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance">
+  <Property type="http://example.com/ns/name">Example &amp; Co</Property>
+  <Link rel="lrdd" template="https://example.com/lrdd?uri={uri}" />
+  <Link rel="https://hopper.at/rel/link"
+        template="https://example.com/{authority}/{rkey}">
+    <Property type="https://atproto.com/ns/collection">app.bsky.feed.post</Property>
+    <Property type="https://atproto.com/ns/rkey" xsi:nil="true" />
+  </Link>
+</XRD>"##,
+        );
+        assert!(webhostmeta.is_ok());
+
+        let webhostmeta = webhostmeta.unwrap();
+        assert_eq!(
+            webhostmeta.properties.get("http://example.com/ns/name"),
+            Some(&"Example & Co".to_string())
+        );
+        assert_eq!(webhostmeta.links.len(), 2);
+        assert_eq!(webhostmeta.links[0].rel, "lrdd");
+        assert!(webhostmeta.links[0].properties.is_empty());
+
+        let link = &webhostmeta.links[1];
+        assert_eq!(link.rel, REL_LINK);
+        assert_eq!(
+            link.template,
+            Some("https://example.com/{authority}/{rkey}".to_string())
+        );
+        assert_eq!(link.properties.len(), 1);
+        assert_eq!(
+            link.properties.get(NS_COLLECTION),
+            Some(&"app.bsky.feed.post".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_xrd_invalid() {
+        assert!(parse_xrd("<html><body>Not found</body></html>").is_err());
+        assert!(parse_xrd("{\"links\": []}").is_err());
+        assert!(parse_xrd("<XRD><Link rel=\"a\"></XRD>").is_err());
+    }
+}